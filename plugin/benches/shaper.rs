@@ -0,0 +1,365 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use envolvigo_lv2::dsp::*;
+use envolvigo_lv2::transport::Transport;
+
+const SAMPLE_RATE: f32 = 48000.0;
+const SECONDS: usize = 2;
+
+/// Decaying noise bursts four times a second, something like a hi-hat.
+fn drum_signal(length: usize) -> Vec<f32> {
+    let hit_distance = SAMPLE_RATE as usize / 4;
+    let decay = (-1.0 / (SAMPLE_RATE * 0.05)).exp();
+    let mut seed = 1u32;
+    let mut envelope = 0.0f32;
+    (0..length).map(|i| {
+        if i % hit_distance == 0 {
+            envelope = 1.0;
+        }
+        envelope *= decay;
+        seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+        envelope * ((seed >> 8) as f32 / (1 << 23) as f32 - 1.0)
+    }).collect()
+}
+
+fn parameters(quality: Quality) -> Parameters {
+    Parameters {
+        enabled: true,
+        use_sidechain: false,
+        key_weights: [1.0; MAX_CHANNELS],
+        attack_boost: 12.0,
+        attack_smooth: 0.035,
+        sustain_boost: -6.0,
+        sustain_smooth: 0.035,
+        attack_width: 0.0,
+        sustain_width: 0.0,
+        attack_tone: 0.0,
+        sustain_tone: 0.0,
+        attack_drive: 0.0,
+        saturation: Saturation::Tape,
+        attack_shape: 0.0,
+        sustain_shape: 0.0,
+        outgain: 0.0,
+        mix: 1.0,
+        sustain_length: None,
+        beat_grid: None,
+        trigger_source: TriggerSource::Audio,
+        onset_detector: OnsetDetector::Level,
+        adaptive_threshold: None,
+        quality,
+        inspector: false
+    }
+}
+
+fn process_signal(shaper: &mut Shaper,
+                  params: &Parameters,
+                  signal: &[Vec<f32>],
+                  output: &mut [Vec<f32>],
+                  block_size: usize) {
+    let transport = Transport::new(SAMPLE_RATE);
+    let sidechain = vec![0.0f32; block_size];
+    let sidechain = [&sidechain[..]];
+    for start in (0..signal[0].len()).step_by(block_size) {
+        let end = (start + block_size).min(signal[0].len());
+        let input: Vec<&[f32]> = signal.iter().map(|ch| &ch[start..end]).collect();
+        let mut out: Vec<&mut [f32]> = output.iter_mut().map(|ch| &mut ch[start..end]).collect();
+        shaper.process(params, &transport, &[], &input, &sidechain, &mut out);
+    }
+}
+
+/// The shaper as it was before the blocks were processed in stages: one
+/// scalar loop over the samples running all the detectors, the state logic
+/// and the gain for each sample. It only knows the boosts, the smooth times,
+/// output level and dry/wet mix, which are all the parameters `parameters()`
+/// sets to something else than neutral. Kept as baseline for the staged
+/// shaper.
+mod per_sample {
+    use std::f64::consts::PI;
+
+    struct Dezipper {
+        target: f64,
+        current_value: f64,
+        coeff: f64,
+    }
+
+    impl Dezipper {
+        fn new(start_value: f64, sample_rate: f64) -> Self {
+            Dezipper {
+                target: start_value,
+                current_value: start_value,
+                coeff: 1.0 - (-2.0 * PI * 25. / sample_rate).exp()
+            }
+        }
+
+        fn process(&mut self) -> f64 {
+            self.current_value += self.coeff * (self.target - self.current_value);
+            self.current_value
+        }
+    }
+
+    struct EnvelopeDetector {
+        attack: f64,
+        release: f64,
+        sample_rate: f64,
+        current_level: f64,
+    }
+
+    impl EnvelopeDetector {
+        fn new(sample_rate: f64) -> Self {
+            EnvelopeDetector {
+                attack: 0.0,
+                release: 0.0,
+                sample_rate,
+                current_level: 0.0,
+            }
+        }
+
+        fn process(&mut self, level: f64) -> f64 {
+            if level >= self.current_level {
+                self.current_level = self.attack * (self.current_level - level) + level;
+            } else {
+                self.current_level = self.release * (self.current_level - level) + level;
+            }
+            self.current_level
+        }
+
+        fn level(&self) -> f64 {
+            self.current_level
+        }
+
+        fn set_params(&mut self, attack_time: f64, release_time: f64) {
+            self.attack = (-1.0 / (self.sample_rate * attack_time)).exp();
+            self.release = (-1.0 / (self.sample_rate * release_time)).exp();
+        }
+
+        fn reset(&mut self, level: f64) {
+            self.current_level = level;
+        }
+    }
+
+    struct BeatDetector {
+        release: f64,
+        current_level: f64,
+        max_level: f64
+    }
+
+    impl BeatDetector {
+        fn process(&mut self, level: f64) -> f64 {
+            if level >= self.current_level {
+                self.current_level = level;
+                self.max_level = self.current_level
+            } else {
+                self.current_level = self.release * (self.current_level - level) + level;
+            }
+            self.current_level
+        }
+    }
+
+    struct InterSamplePeak {
+        history: [f32; 3]
+    }
+
+    impl InterSamplePeak {
+        fn process(&mut self, v: f32, oversampling: usize) -> f32 {
+            let [y0, y1, y2] = self.history;
+            self.history = [y1, y2, v];
+
+            if oversampling <= 1 {
+                return v.abs();
+            }
+
+            (1..oversampling).fold(y2.abs(), |peak, k| {
+                let t = k as f32 / oversampling as f32;
+                let c1 = 0.5 * (y2 - y0);
+                let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * v;
+                let c3 = 0.5 * (v - y0) + 1.5 * (y1 - y2);
+                peak.max((((c3 * t + c2) * t + c1) * t + y1).abs())
+            })
+        }
+    }
+
+    #[derive(PartialEq, Clone, Copy)]
+    enum State {
+        Attack,
+        Release,
+        Idle
+    }
+
+    #[allow(non_snake_case)]
+    fn from_dB(v: f64) -> f64 {
+        10.0f64.powf(0.05 * v)
+    }
+
+    pub struct Shaper {
+        input_peaks: Vec<InterSamplePeak>,
+        beat_detector: BeatDetector,
+
+        attack_smooth: EnvelopeDetector,
+        sustain_smooth: EnvelopeDetector,
+        attack_slow: EnvelopeDetector,
+        attack_fast: EnvelopeDetector,
+        release_slow: EnvelopeDetector,
+        release_fast: EnvelopeDetector,
+
+        attack_boost: Dezipper,
+        sustain_boost: Dezipper,
+        result_gain: EnvelopeDetector,
+        outgain: Dezipper,
+        mix: Dezipper,
+
+        sustain_smooth_time: f64,
+        state: State,
+    }
+
+    impl Shaper {
+        pub fn new(sample_rate: f64, channels: usize, params: &super::Parameters) -> Self {
+            let envelope = |attack_time, release_time| {
+                let mut detector = EnvelopeDetector::new(sample_rate);
+                detector.set_params(attack_time, release_time);
+                detector
+            };
+            Shaper {
+                input_peaks: (0..channels).map(|_| InterSamplePeak { history: [0.0; 3] }).collect(),
+                beat_detector: BeatDetector {
+                    release: (-1.0 / (sample_rate * 0.2)).exp(),
+                    current_level: 0.0,
+                    max_level: 0.0
+                },
+
+                attack_smooth: envelope(0.0, params.attack_smooth),
+                sustain_smooth: envelope(params.sustain_smooth, params.sustain_smooth),
+                attack_slow: envelope(0.02, 5.0),
+                attack_fast: envelope(0.0, 0.02),
+                release_slow: envelope(0.02, 0.025),
+                release_fast: envelope(0.01, 0.02),
+
+                attack_boost: Dezipper::new(params.attack_boost, sample_rate),
+                sustain_boost: Dezipper::new(params.sustain_boost, sample_rate),
+                result_gain: EnvelopeDetector::new(sample_rate),
+                outgain: Dezipper::new(from_dB(params.outgain), sample_rate),
+                mix: Dezipper::new(params.mix, sample_rate),
+
+                sustain_smooth_time: params.sustain_smooth,
+                state: State::Idle,
+            }
+        }
+
+        pub fn process(&mut self, oversampling: usize, input: &[&[f32]], output: &mut [&mut [f32]]) {
+            for i in 0..input[0].len() {
+                let attack_boost = self.attack_boost.process();
+                let sustain_boost = self.sustain_boost.process();
+
+                let lvl = Iterator::zip(input.iter(), self.input_peaks.iter_mut())
+                    .fold(0.0f32, |acc, (v, peak)| acc.max(peak.process(v[i], oversampling)));
+                let lvl = lvl as f64;
+
+                let old_lvl = self.beat_detector.current_level;
+                let beat_detect = self.beat_detector.process(lvl);
+
+                if beat_detect > old_lvl {
+                    if self.state != State::Attack {
+                        self.attack_fast.reset(0.0);
+                        self.attack_slow.reset(0.0);
+                        self.attack_smooth.reset(self.result_gain.level());
+                    }
+                    self.state = State::Attack;
+                }
+
+                let gain = match self.state {
+                    State::Attack => {
+                        let atk_fast = self.attack_fast.process(lvl);
+                        let atk_slow = self.attack_slow.process(lvl);
+                        let delta_atk = atk_fast - atk_slow;
+
+                        let pregain = self.attack_smooth.process(
+                            from_dB(delta_atk / self.beat_detector.max_level)
+                        );
+
+                        if pregain < 1.0 {
+                            self.state = State::Release;
+                            self.release_fast.reset(atk_slow);
+                            self.release_slow.reset(0.0);
+                            self.sustain_smooth.reset(pregain);
+                        }
+                        pregain.powf(attack_boost)
+                    }
+                    State::Release => {
+                        let rel_fast = self.release_fast.process(lvl);
+                        let rel_slow = self.release_slow.process(lvl);
+
+                        let delta_rel = rel_fast - rel_slow;
+                        let pregain = self.sustain_smooth.process(
+                            from_dB(delta_rel / self.attack_slow.level()
+                                    * (15.0 + 3.0 * self.sustain_smooth_time.log10()) / 7.0)
+                        );
+                        if pregain < 1.0 {
+                            self.state = State::Idle;
+                        }
+                        pregain.powf(sustain_boost)
+                    }
+                    State::Idle => self.sustain_smooth.process(1.0)
+                };
+                let gain = self.result_gain.process(gain);
+
+                let mix = self.mix.process();
+                let gain = gain * self.outgain.process();
+                for (input, output) in Iterator::zip(input.iter(), output.iter_mut()) {
+                    let input = input[i] as f64;
+                    output[i] = (input * gain * mix + input * (1.0 - mix)) as f32;
+                }
+            }
+        }
+    }
+}
+
+fn process_signal_per_sample(shaper: &mut per_sample::Shaper,
+                             oversampling: usize,
+                             signal: &[Vec<f32>],
+                             output: &mut [Vec<f32>],
+                             block_size: usize) {
+    for start in (0..signal[0].len()).step_by(block_size) {
+        let end = (start + block_size).min(signal[0].len());
+        let input: Vec<&[f32]> = signal.iter().map(|ch| &ch[start..end]).collect();
+        let mut out: Vec<&mut [f32]> = output.iter_mut().map(|ch| &mut ch[start..end]).collect();
+        shaper.process(oversampling, &input, &mut out);
+    }
+}
+
+fn bench_shaper(c: &mut Criterion) {
+    let length = SAMPLE_RATE as usize * SECONDS;
+
+    for &(name, quality) in &[("realtime", REALTIME_QUALITY), ("offline", OFFLINE_QUALITY)] {
+        let mut group = c.benchmark_group(format!("shaper_{}", name));
+        group.throughput(Throughput::Elements(length as u64));
+
+        for &channels in &[1, 2] {
+            let signal: Vec<Vec<f32>> = (0..channels).map(|_| drum_signal(length)).collect();
+            let mut output: Vec<Vec<f32>> = (0..channels).map(|_| vec![0.0; length]).collect();
+            let params = parameters(quality);
+
+            for &block_size in &[32, 256, 1024] {
+                let id = BenchmarkId::new(format!("{}ch", channels), block_size);
+                group.bench_with_input(id, &block_size, |b, &block_size| {
+                    let mut shaper = Shaper::new(SAMPLE_RATE as f64, block_size, channels, 1);
+                    b.iter(|| process_signal(&mut shaper, &params, &signal, &mut output, block_size))
+                });
+
+                // The baseline has no delay lines, crossovers or gain
+                // oversampling, so it only does the same work in real time.
+                if quality != REALTIME_QUALITY {
+                    continue;
+                }
+                let id = BenchmarkId::new(format!("{}ch_per_sample", channels), block_size);
+                group.bench_with_input(id, &block_size, |b, &block_size| {
+                    let oversampling = quality.detector_oversampling;
+                    let mut shaper = per_sample::Shaper::new(SAMPLE_RATE as f64, channels, &params);
+                    b.iter(|| process_signal_per_sample(&mut shaper, oversampling, &signal, &mut output, block_size))
+                });
+            }
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_shaper);
+criterion_main!(benches);
@@ -0,0 +1,465 @@
+use urids::protocol::{AudioData, InspectorSignals, StereoSignals};
+
+use crate::dsp::{to_dB, Envelopes};
+
+// The UI does not get the signals sample by sample but decimated to buckets
+// of a fixed number of frames. For every bucket the minimum and the maximum
+// of the signals are sent. The bucket size follows the resolution the UI asks
+// for, which is usually about one bucket per pixel of the oscilloscope.
+// The buckets are collected over the blocks until they are sent.
+
+/// The resolution in buckets per second used until the UI asks for one
+pub const DEFAULT_RESOLUTION: f32 = 2000.0;
+
+/// Which channels the oscilloscope shows
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ChannelView {
+    Max,
+    Left,
+    Right,
+    Mid,
+    Side,
+}
+
+impl ChannelView {
+    /// The level of the view at frame `i`. Only two channels can be shown
+    /// other than by their maximum.
+    pub fn level<S: AsRef<[f32]>>(self, channels: &[S], i: usize) -> f32 {
+        match (self, channels) {
+            (ChannelView::Left, [left, _]) => left.as_ref()[i].abs(),
+            (ChannelView::Right, [_, right]) => right.as_ref()[i].abs(),
+            (ChannelView::Mid, [left, right]) => ((left.as_ref()[i] + right.as_ref()[i]) / 2.0).abs(),
+            (ChannelView::Side, [left, right]) => ((left.as_ref()[i] - right.as_ref()[i]) / 2.0).abs(),
+            _ => channels.iter().fold(0.0f32, |acc, channel| acc.max(channel.as_ref()[i].abs())),
+        }
+    }
+}
+
+/// The values of all the signals at one frame. All but the threshold are
+/// linear amplitudes, the threshold is already in dB. The input and the
+/// output are combined by the channel view, the single channels are only
+/// looked at for stereo. The envelopes are only looked at in inspector
+/// mode.
+pub struct Frame {
+    pub gain: f32,
+    pub input: f32,
+    pub sidechain: f32,
+    pub threshold: f32,
+    pub output: f32,
+    pub input_channels: [f32; 2],
+    pub output_channels: [f32; 2],
+    pub envelopes: Envelopes,
+}
+
+struct Signal {
+    min: f32,
+    max: f32,
+    linear: bool,
+    buckets: Vec<f32>,
+}
+
+impl Signal {
+    fn new(linear: bool, max_buckets: usize) -> Self {
+        Signal {
+            min: f32::INFINITY,
+            max: f32::NEG_INFINITY,
+            linear,
+            buckets: Vec::with_capacity(2 * max_buckets),
+        }
+    }
+
+    fn add(&mut self, v: f32) {
+        self.min = self.min.min(v);
+        self.max = self.max.max(v);
+    }
+
+    fn complete_bucket(&mut self) {
+        if self.linear {
+            self.buckets.push(to_dB(&self.min));
+            self.buckets.push(to_dB(&self.max));
+        } else {
+            self.buckets.push(self.min);
+            self.buckets.push(self.max);
+        }
+        self.reset_bucket();
+    }
+
+    fn reset_bucket(&mut self) {
+        self.min = f32::INFINITY;
+        self.max = f32::NEG_INFINITY;
+    }
+}
+
+pub struct Decimator {
+    sample_rate: f32,
+    requested_resolution: f32,
+    bucket_size: usize,
+    /// Smaller buckets would not fit into the notify port, depending on
+    /// whether the inspector signals are sent as well.
+    min_bucket_size: usize,
+    min_inspector_bucket_size: usize,
+    max_block_length: usize,
+    max_buckets: usize,
+    max_inspector_buckets: usize,
+    stereo: bool,
+    inspector: bool,
+
+    frames: usize,
+    first_frame: u64,
+
+    gain: Signal,
+    input: Signal,
+    sidechain: Signal,
+    threshold: Signal,
+    output: Signal,
+
+    input_left: Signal,
+    input_right: Signal,
+    output_left: Signal,
+    output_right: Signal,
+
+    attack_fast: Signal,
+    attack_slow: Signal,
+    release_fast: Signal,
+    release_slow: Signal,
+    beat_level: Signal,
+    pregain: Signal,
+}
+
+impl Decimator {
+    /// `max_buckets` and `max_inspector_buckets` are the numbers of buckets
+    /// the notify port can take in one block without and with the inspector
+    /// signals. No more are collected until they are sent. The single
+    /// channels are only sent if `stereo` is set.
+    pub fn new(sample_rate: f32,
+               max_block_length: usize,
+               max_buckets: usize,
+               max_inspector_buckets: usize,
+               stereo: bool) -> Self {
+        let min_bucket_size = smallest_bucket_size(max_block_length, max_buckets);
+        // at most one bucket per frame and the one that was pending if a
+        // block has to be sent on its own
+        let capacity = max_buckets.max(max_block_length + 1);
+        Decimator {
+            sample_rate,
+            requested_resolution: DEFAULT_RESOLUTION,
+            bucket_size: bucket_size(sample_rate, DEFAULT_RESOLUTION).max(min_bucket_size),
+            min_bucket_size,
+            min_inspector_bucket_size: smallest_bucket_size(max_block_length, max_inspector_buckets),
+            max_block_length,
+            max_buckets,
+            max_inspector_buckets,
+            stereo,
+            inspector: false,
+
+            frames: 0,
+            first_frame: 0,
+
+            gain: Signal::new(true, capacity),
+            input: Signal::new(true, capacity),
+            sidechain: Signal::new(true, capacity),
+            threshold: Signal::new(false, capacity),
+            output: Signal::new(true, capacity),
+
+            input_left: Signal::new(true, capacity),
+            input_right: Signal::new(true, capacity),
+            output_left: Signal::new(true, capacity),
+            output_right: Signal::new(true, capacity),
+
+            attack_fast: Signal::new(true, capacity),
+            attack_slow: Signal::new(true, capacity),
+            release_fast: Signal::new(true, capacity),
+            release_slow: Signal::new(true, capacity),
+            beat_level: Signal::new(true, capacity),
+            pregain: Signal::new(true, capacity),
+        }
+    }
+
+    /// Sets the resolution the UI asks for, as far as the notify port allows
+    /// for it. The buckets not sent yet are dropped.
+    pub fn set_resolution(&mut self, buckets_per_second: f32) {
+        if !buckets_per_second.is_finite() || buckets_per_second <= 0.0 {
+            return;
+        }
+        self.requested_resolution = buckets_per_second;
+        self.update_bucket_size();
+    }
+
+    /// Switches the inspector signals on or off. The buckets not sent yet are
+    /// dropped as their inspector signals are incomplete.
+    pub fn set_inspector(&mut self, inspector: bool) {
+        if inspector == self.inspector {
+            return;
+        }
+        self.inspector = inspector;
+        self.update_bucket_size();
+        self.reset();
+    }
+
+    fn update_bucket_size(&mut self) {
+        let min_bucket_size = if self.inspector {
+            self.min_inspector_bucket_size
+        } else {
+            self.min_bucket_size
+        };
+        let bucket_size = bucket_size(self.sample_rate, self.requested_resolution).max(min_bucket_size);
+        if bucket_size == self.bucket_size {
+            return;
+        }
+        self.bucket_size = bucket_size;
+        self.reset();
+    }
+
+    /// Drops a pending bucket and the buckets not sent yet, as they don't
+    /// continue with the next frame.
+    pub fn reset(&mut self) {
+        self.frames = 0;
+        for signal in self.signals_mut().iter_mut() {
+            signal.reset_bucket();
+            signal.buckets.clear();
+        }
+    }
+
+    /// The actual resolution, which can differ from the requested one as a
+    /// bucket consists of a whole number of frames.
+    pub fn resolution(&self) -> f32 {
+        self.sample_rate / self.bucket_size as f32
+    }
+
+    /// `frame` is the absolute frame the block starts at.
+    pub fn start_block(&mut self, frame: u64) {
+        if self.is_empty() {
+            self.first_frame = frame - self.frames as u64;
+        }
+    }
+
+    /// Drops the buckets that have been sent.
+    pub fn clear(&mut self) {
+        for signal in self.signals_mut().iter_mut() {
+            signal.buckets.clear();
+        }
+    }
+
+    /// The number of buckets not sent yet
+    pub fn len(&self) -> usize {
+        self.input.buckets.len() / 2
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.input.buckets.is_empty()
+    }
+
+    /// Whether the buckets of another block might not fit into one message
+    /// anymore, so that they have to be sent now.
+    pub fn is_full(&self) -> bool {
+        let max_buckets = if self.inspector {
+            self.max_inspector_buckets
+        } else {
+            self.max_buckets
+        };
+        self.len() + (self.frames + self.max_block_length) / self.bucket_size > max_buckets
+    }
+
+    pub fn push(&mut self, frame: Frame) {
+        self.gain.add(frame.gain);
+        self.input.add(frame.input);
+        self.sidechain.add(frame.sidechain);
+        self.threshold.add(frame.threshold);
+        self.output.add(frame.output);
+        if self.stereo {
+            self.input_left.add(frame.input_channels[0]);
+            self.input_right.add(frame.input_channels[1]);
+            self.output_left.add(frame.output_channels[0]);
+            self.output_right.add(frame.output_channels[1]);
+        }
+        if self.inspector {
+            self.attack_fast.add(frame.envelopes.attack_fast);
+            self.attack_slow.add(frame.envelopes.attack_slow);
+            self.release_fast.add(frame.envelopes.release_fast);
+            self.release_slow.add(frame.envelopes.release_slow);
+            self.beat_level.add(frame.envelopes.beat_level);
+            self.pregain.add(frame.envelopes.pregain);
+        }
+
+        self.frames += 1;
+        if self.frames == self.bucket_size {
+            self.frames = 0;
+            for signal in self.main_signals_mut().iter_mut() {
+                signal.complete_bucket();
+            }
+            if self.stereo {
+                for signal in self.stereo_signals_mut().iter_mut() {
+                    signal.complete_bucket();
+                }
+            }
+            if self.inspector {
+                for signal in self.inspector_signals_mut().iter_mut() {
+                    signal.complete_bucket();
+                }
+            }
+        }
+    }
+
+    /// The buckets completed since they have been sent the last time, `None`
+    /// if there are none.
+    pub fn audio_data(&self, with_sidechain: bool, with_threshold: bool) -> Option<AudioData<'_>> {
+        if self.is_empty() {
+            return None;
+        }
+        Some(AudioData {
+            buckets_per_second: self.resolution(),
+            first_frame: self.first_frame,
+            gain_signal: &self.gain.buckets,
+            input_signal: &self.input.buckets,
+            sidechain_signal: if with_sidechain { Some(self.sidechain.buckets.as_slice()) } else { None },
+            threshold_signal: if with_threshold { Some(self.threshold.buckets.as_slice()) } else { None },
+            output_signal: &self.output.buckets,
+            stereo: if self.stereo {
+                Some(StereoSignals {
+                    input_left: &self.input_left.buckets,
+                    input_right: &self.input_right.buckets,
+                    output_left: &self.output_left.buckets,
+                    output_right: &self.output_right.buckets,
+                })
+            } else {
+                None
+            },
+            inspector: if self.inspector {
+                Some(InspectorSignals {
+                    attack_fast: &self.attack_fast.buckets,
+                    attack_slow: &self.attack_slow.buckets,
+                    release_fast: &self.release_fast.buckets,
+                    release_slow: &self.release_slow.buckets,
+                    beat_level: &self.beat_level.buckets,
+                    pregain: &self.pregain.buckets,
+                })
+            } else {
+                None
+            },
+        })
+    }
+
+    fn signals_mut(&mut self) -> [&mut Signal; 15] {
+        [&mut self.gain, &mut self.input, &mut self.sidechain, &mut self.threshold, &mut self.output,
+         &mut self.input_left, &mut self.input_right, &mut self.output_left, &mut self.output_right,
+         &mut self.attack_fast, &mut self.attack_slow, &mut self.release_fast,
+         &mut self.release_slow, &mut self.beat_level, &mut self.pregain]
+    }
+
+    fn main_signals_mut(&mut self) -> [&mut Signal; 5] {
+        [&mut self.gain, &mut self.input, &mut self.sidechain, &mut self.threshold, &mut self.output]
+    }
+
+    fn stereo_signals_mut(&mut self) -> [&mut Signal; 4] {
+        [&mut self.input_left, &mut self.input_right, &mut self.output_left, &mut self.output_right]
+    }
+
+    fn inspector_signals_mut(&mut self) -> [&mut Signal; 6] {
+        [&mut self.attack_fast, &mut self.attack_slow, &mut self.release_fast,
+         &mut self.release_slow, &mut self.beat_level, &mut self.pregain]
+    }
+}
+
+fn smallest_bucket_size(max_block_length: usize, max_buckets: usize) -> usize {
+    // a block can complete one bucket more than it has frames for, as a
+    // bucket may be pending from the last block
+    max_block_length
+        .div_ceil(max_buckets.saturating_sub(1).max(1))
+        .max(1)
+}
+
+fn bucket_size(sample_rate: f32, buckets_per_second: f32) -> usize {
+    ((sample_rate / buckets_per_second).round() as usize).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(level: f32, threshold: f32) -> Frame {
+        Frame {
+            gain: 1.0,
+            input: level,
+            sidechain: 0.0,
+            threshold,
+            output: level,
+            input_channels: [level; 2],
+            output_channels: [level; 2],
+            envelopes: Envelopes::default(),
+        }
+    }
+
+    /// Buckets of four frames at 48 kHz
+    fn decimator(max_block_length: usize, max_buckets: usize, max_inspector_buckets: usize) -> Decimator {
+        let mut decimator = Decimator::new(48000.0, max_block_length, max_buckets, max_inspector_buckets, false);
+        decimator.set_resolution(12000.0);
+        decimator
+    }
+
+    #[test]
+    fn buckets_hold_the_minimum_and_the_maximum() {
+        let mut decimator = decimator(64, 100, 50);
+        assert_eq!(decimator.resolution(), 12000.0);
+        decimator.start_block(4096);
+        let levels = [0.1, 1.0, 0.5, 0.01, 0.5, 0.5, 0.25, 0.5, 0.3];
+        let thresholds = [-30.0, -20.0, -25.0, -30.0, -40.0, -40.0, -40.0, -40.0, -50.0];
+        for (&level, &threshold) in Iterator::zip(levels.iter(), thresholds.iter()) {
+            decimator.push(frame(level, threshold));
+        }
+
+        // the last frame is pending in the third bucket
+        assert_eq!(decimator.len(), 2);
+        let audio_data = decimator.audio_data(false, true).unwrap();
+        assert_eq!(audio_data.first_frame, 4096);
+        assert_eq!(audio_data.input_signal, &[to_dB(&0.01), 0.0, to_dB(&0.25), to_dB(&0.5)]);
+        assert_eq!(audio_data.output_signal, audio_data.input_signal);
+        assert_eq!(audio_data.gain_signal, &[0.0; 4]);
+        // the threshold is already in dB
+        assert_eq!(audio_data.threshold_signal, Some(&[-30.0, -20.0, -40.0, -40.0][..]));
+        assert_eq!(audio_data.sidechain_signal, None);
+        assert!(audio_data.stereo.is_none());
+        assert!(audio_data.inspector.is_none());
+
+        // the pending bucket is continued after the sent ones are dropped
+        decimator.clear();
+        assert!(decimator.audio_data(false, true).is_none());
+        decimator.start_block(4105);
+        for _ in 0..3 {
+            decimator.push(frame(0.1, -50.0));
+        }
+        let audio_data = decimator.audio_data(false, true).unwrap();
+        assert_eq!(audio_data.first_frame, 4104);
+        assert_eq!(audio_data.input_signal, &[to_dB(&0.1), to_dB(&0.3)]);
+    }
+
+    #[test]
+    fn full_when_another_block_might_not_fit() {
+        // up to two buckets per block of eight frames
+        let mut decimator = decimator(8, 10, 5);
+        assert!(!decimator.is_full());
+        for _ in 0..32 {
+            decimator.push(frame(0.5, -30.0));
+        }
+        assert_eq!(decimator.len(), 8);
+        assert!(!decimator.is_full());
+        for _ in 0..4 {
+            decimator.push(frame(0.5, -30.0));
+        }
+        assert!(decimator.is_full());
+
+        decimator.clear();
+        assert!(!decimator.is_full());
+
+        // fewer buckets fit with the inspector signals
+        decimator.set_inspector(true);
+        for _ in 0..12 {
+            decimator.push(frame(0.5, -30.0));
+        }
+        assert_eq!(decimator.len(), 3);
+        assert!(!decimator.is_full());
+        for _ in 0..4 {
+            decimator.push(frame(0.5, -30.0));
+        }
+        assert!(decimator.is_full());
+    }
+}
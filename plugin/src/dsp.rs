@@ -0,0 +1,1666 @@
+use std::f64::consts::{LN_10, PI};
+use std::mem;
+
+use urids::shape::{reshape, shape_exponent};
+
+use crate::onset::SpectralFlux;
+use crate::transport::Transport;
+
+// All the detectors and smoothing filters are processed in double precision
+// as their coefficients get very close to 1.0 at high sample rates.
+
+struct Dezipper {
+    target: f64,
+    current_value: f64,
+    coeff: f64,
+}
+
+impl Dezipper {
+    fn new(start_value: f64, sample_rate: f64) -> Self {
+        Dezipper {
+            target: start_value,
+            current_value: start_value,
+            coeff: 1.0 - (-2.0 * PI * 25. / sample_rate).exp()
+        }
+    }
+
+    fn set_value(&mut self, v: f64) {
+        self.target = v
+    }
+
+    fn process(&mut self) -> f64 {
+        self.current_value += self.coeff * (self.target - self.current_value);
+        self.current_value
+    }
+
+    /// Does the same as calling `process()` `n` times.
+    fn skip(&mut self, n: usize) {
+        let decay = (1.0 - self.coeff).powi(n as i32);
+        self.current_value = self.target + decay * (self.current_value - self.target);
+    }
+}
+
+
+struct EnvelopeDetector {
+    attack: f64,
+    release: f64,
+    sample_rate: f64,
+
+    current_level: f64,
+    y1: f64
+}
+
+impl EnvelopeDetector {
+    fn new(sample_rate: f64) -> Self {
+        EnvelopeDetector {
+            attack: 0.0,
+            release: 0.0,
+            sample_rate,
+            current_level: 0.0,
+            y1: 0.0
+        }
+    }
+
+    fn process(&mut self, level: f64) -> f64 {
+        if level >= self.current_level {
+            self.current_level = self.attack * (self.current_level - level) + level;
+        } else {
+            self.current_level = self.release * (self.current_level - level) + level;
+        }
+        self.current_level
+    }
+
+    fn level(&self) -> f64 {
+        self.current_level
+    }
+
+    fn set_params(&mut self, attack_time: f64, release_time: f64) {
+        self.attack = (-1.0 / (self.sample_rate * attack_time)).exp();
+//        self.y1 = self.attack;
+        self.release = (-1.0 / (self.sample_rate * release_time)).exp();
+    }
+
+    fn reset(&mut self, level: f64) {
+        self.y1 = self.attack;
+        self.current_level = level;
+    }
+}
+
+
+struct BeatDetector {
+    release: f64,
+
+    current_level: f64,
+    max_level: f64
+}
+
+impl BeatDetector {
+    fn new(sample_rate: f64, release_time: f64) -> Self {
+        BeatDetector {
+            release: (-1.0 / (sample_rate * release_time)).exp(),
+
+            current_level: 0.0,
+            max_level: 0.0
+        }
+    }
+
+    fn process(&mut self, level: f64) -> f64 {
+        if level >= self.current_level {
+            self.current_level = level;
+            self.max_level = self.current_level
+        } else {
+            self.current_level = self.release * (self.current_level - level) + level;
+        }
+        self.current_level
+    }
+
+    fn max_level(&self) -> f64 {
+        self.max_level
+    }
+
+    fn level(&self) -> f64 {
+        self.current_level
+    }
+}
+
+
+/// Splits the signal for the tone control at 1 kHz. The high band is the
+/// input minus the low band.
+struct Crossover {
+    coeff: f64,
+    low: f64
+}
+
+impl Crossover {
+    fn new(sample_rate: f64) -> Self {
+        Crossover {
+            coeff: 1.0 - (-2.0 * PI * 1000.0 / sample_rate).exp(),
+            low: 0.0
+        }
+    }
+
+    fn process(&mut self, v: f32) -> f32 {
+        self.low += self.coeff * (v as f64 - self.low);
+        self.low as f32
+    }
+}
+
+
+/// The character of the saturation during the attack
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Saturation {
+    /// soft and symmetric
+    Tape,
+    /// soft and asymmetric, adds even harmonics
+    Tube,
+    /// hard clipping
+    Hard
+}
+
+impl Saturation {
+    fn curve(&self, v: f32) -> f32 {
+        match self {
+            Saturation::Tape => v.tanh(),
+            Saturation::Tube => if v >= 0.0 { v.tanh() } else { (1.5 * v).tanh() / 1.5 },
+            Saturation::Hard => v.max(-1.0).min(1.0),
+        }
+    }
+
+    /// Drives `v` by `drive` into the curve and back. The more drive, the
+    /// more of the saturated signal is blended in, so that a drive of 1.0
+    /// leaves the signal clean.
+    fn process(&self, v: f32, drive: f32) -> f32 {
+        let amount = 1.0 - 1.0 / drive;
+        (v + amount * self.curve(v * drive)) / drive
+    }
+
+    /// Like `process()`, but the part the curve adds to the linear signal is
+    /// calculated at `oversampling` points around the sample and averaged.
+    /// The points are interpolated from `window`, the two samples before and
+    /// after the sample and the sample itself. The averaging attenuates the
+    /// harmonics above the Nyquist frequency before they fold back.
+    fn process_oversampled(&self, window: &[f32], drive: f32, oversampling: usize) -> f32 {
+        let amount = 1.0 - 1.0 / drive;
+        let residual: f32 = (0..oversampling).map(|k| {
+            let t = (k as f32 + 0.5) / oversampling as f32 - 0.5;
+            let v = if t < 0.0 {
+                cubic_interpolate(window[0], window[1], window[2], window[3], t + 1.0)
+            } else {
+                cubic_interpolate(window[1], window[2], window[3], window[4], t)
+            };
+            self.curve(v * drive) - v * drive
+        }).sum();
+        window[2] + amount * residual / (oversampling as f32 * drive)
+    }
+}
+
+
+/// The factors to get from an input sample to an output sample. They
+/// include the gain of the current state, output level and dry/wet mix.
+#[derive(Clone, Copy)]
+struct ApplyGains {
+    low: f32,
+    high: f32,
+    /// added for the side signal of stereo input
+    side: f32,
+    dry: f32,
+}
+
+impl ApplyGains {
+    /// Mixes the input sample `v` and the same sample after the saturation.
+    fn shape(&self, v: f32, driven: f32, crossover: &mut Crossover, tone: bool) -> f32 {
+        let wet = if tone {
+            let low = crossover.process(driven);
+            low * self.low + (driven - low) * self.high
+        } else {
+            driven * self.low
+        };
+        wet + v * self.dry
+    }
+}
+
+
+/// Follows the average level in dB of the last few seconds.
+struct LevelAverage {
+    coeff: f64,
+    average: f64
+}
+
+impl LevelAverage {
+    fn new(sample_rate: f64, time: f64) -> Self {
+        LevelAverage {
+            coeff: (-1.0 / (sample_rate * time)).exp(),
+            average: -100.0
+        }
+    }
+
+    fn process(&mut self, level: f64) -> f64 {
+        let level_db = 20.0 * level.max(1e-5).log10();
+        self.average = self.coeff * (self.average - level_db) + level_db;
+        self.average
+    }
+}
+
+
+/// Estimates the peak level between two samples by cubic interpolation. This
+/// delays the detected level by one sample.
+struct InterSamplePeak {
+    history: [f32; 3]
+}
+
+impl InterSamplePeak {
+    fn new() -> Self {
+        InterSamplePeak {
+            history: [0.0; 3]
+        }
+    }
+
+    fn process(&mut self, v: f32, oversampling: usize) -> f32 {
+        let [y0, y1, y2] = self.history;
+        self.history = [y1, y2, v];
+
+        (1..oversampling).fold(y2.abs(), |peak, k| {
+            let t = k as f32 / oversampling as f32;
+            peak.max(cubic_interpolate(y0, y1, y2, v, t).abs())
+        })
+    }
+
+    /// Keeps the history up to date while the block is not oversampled.
+    fn track(&mut self, block: &[f32]) {
+        for &v in &block[block.len().saturating_sub(3)..] {
+            let [_, y1, y2] = self.history;
+            self.history = [y1, y2, v];
+        }
+    }
+}
+
+fn cubic_interpolate(y0: f32, y1: f32, y2: f32, y3: f32, t: f32) -> f32 {
+    let c1 = 0.5 * (y2 - y0);
+    let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+    let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+    ((c3 * t + c2) * t + c1) * t + y1
+}
+
+
+/// Delays a signal by `length` frames. The line keeps `DELAY_HISTORY` more
+/// frames of the past, so that there is a window of samples around every
+/// delayed frame.
+struct Delay {
+    line: Vec<f32>,
+    length: usize
+}
+
+const DELAY_HISTORY: usize = 2;
+
+impl Delay {
+    fn new(capacity: usize) -> Self {
+        Delay {
+            line: Vec::with_capacity(capacity),
+            length: 0
+        }
+    }
+
+    /// Changes the delay. The delayed signal starts over with silence.
+    fn set_length(&mut self, length: usize) {
+        self.length = length;
+        self.line.clear();
+        self.line.resize(DELAY_HISTORY + length, 0.0);
+    }
+
+    fn push(&mut self, block: &[f32]) {
+        let consumed = self.line.len() - DELAY_HISTORY - self.length;
+        self.line.drain(..consumed);
+        self.line.extend_from_slice(block);
+    }
+
+    /// The delayed block of `n_samples` frames after `push()`
+    fn delayed(&self, n_samples: usize) -> &[f32] {
+        &self.line[DELAY_HISTORY..DELAY_HISTORY + n_samples]
+    }
+
+    /// The frames from `DELAY_HISTORY` before to `DELAY_HISTORY` after the
+    /// frame `i` of the delayed block. The frames after are only there if
+    /// the delay is at least `DELAY_HISTORY` frames.
+    fn window(&self, i: usize) -> &[f32] {
+        &self.line[i..i + 2 * DELAY_HISTORY + 1]
+    }
+}
+
+
+/// Settings that trade CPU load for accuracy. We use the expensive ones
+/// when the host renders faster than real time.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct Quality {
+    pub detector_oversampling: usize,
+    /// Oversampling of the saturation. It needs the lookahead to see the
+    /// samples after the current one.
+    pub gain_oversampling: usize,
+    /// Time in seconds the audio is delayed against the key signal, so that
+    /// the gain is already rising when a hit arrives. It is reported to the
+    /// host as latency.
+    pub lookahead: f64
+}
+
+impl Quality {
+    fn lookahead_frames(&self, sample_rate: f64) -> usize {
+        let frames = (self.lookahead * sample_rate).round() as usize;
+        if self.gain_oversampling > 1 {
+            frames.max(DELAY_HISTORY)
+        } else {
+            frames
+        }
+    }
+}
+
+pub const REALTIME_QUALITY: Quality = Quality {
+    detector_oversampling: 1,
+    gain_oversampling: 1,
+    lookahead: 0.0
+};
+
+pub const OFFLINE_QUALITY: Quality = Quality {
+    detector_oversampling: 8,
+    gain_oversampling: 4,
+    lookahead: 0.002
+};
+
+
+/// Hits are only shaped on the steps of the bar whose bit is set in `mask`.
+/// A hit that runs into a step that is not set ends there. Without a known
+/// position, for example while the transport is stopped, all steps are
+/// set.
+#[derive(Clone, Copy, Debug)]
+pub struct BeatGrid {
+    pub steps: u32,
+    pub mask: u32
+}
+
+/// Time in seconds the gain takes back to unity when a hit ends on a step
+/// that is not set, just long enough to avoid a click
+const GRID_FADE: f64 = 0.002;
+
+impl BeatGrid {
+    fn is_open(&self, transport: &Transport, frame: i64) -> bool {
+        transport
+            .step_at(self.steps, frame)
+            .map_or(true, |step| self.mask & (1 << step) != 0)
+    }
+}
+
+
+/// Where the hits that start the attack phase come from
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum TriggerSource {
+    Audio,
+    Midi
+}
+
+/// How the beginning of a hit is detected
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum OnsetDetector {
+    /// The rising edge of the broadband level, good for isolated drums
+    Level,
+    /// Spectral flux, works also on dense mixes and tonal material
+    SpectralFlux
+}
+
+/// A MIDI note on at `frame` of the current block. The velocity is
+/// normalized to 0.0..1.0.
+#[derive(Clone, Copy, Debug)]
+pub struct Trigger {
+    pub frame: usize,
+    pub velocity: f32
+}
+
+
+/// The maximum number of channels of a plugin variant (7.1 surround)
+pub const MAX_CHANNELS: usize = 8;
+
+const UNITY_WEIGHTS: [f32; MAX_CHANNELS] = [1.0; MAX_CHANNELS];
+
+/// The parameters of a block, already limited to their valid ranges.
+pub struct Parameters {
+    pub enabled: bool,
+    pub use_sidechain: bool,
+    pub key_weights: [f32; MAX_CHANNELS],
+    pub attack_boost: f64,
+    pub attack_smooth: f64,
+    pub sustain_boost: f64,
+    pub sustain_smooth: f64,
+    /// Extra boost in dB of the side signal over the mid signal during the
+    /// attack and the sustain. Only used for two channels.
+    pub attack_width: f64,
+    pub sustain_width: f64,
+    /// Tilts the boost towards the high (positive) or the low (negative)
+    /// frequencies during the attack and the sustain, from -1.0 to 1.0
+    pub attack_tone: f64,
+    pub sustain_tone: f64,
+    /// Drive in dB into the saturation at the peak of the attack, 0.0 for
+    /// no saturation
+    pub attack_drive: f64,
+    pub saturation: Saturation,
+    /// Shape of the curve from the detector difference to the gain during
+    /// the attack and the sustain, from -1.0 (gentle) to 1.0 (aggressive)
+    pub attack_shape: f64,
+    pub sustain_shape: f64,
+    pub outgain: f64,
+    pub mix: f64,
+    pub sustain_length: Option<usize>,
+    pub beat_grid: Option<BeatGrid>,
+    pub trigger_source: TriggerSource,
+    pub onset_detector: OnsetDetector,
+    /// Margin in dB above the average level that the level has to exceed
+    /// to start a hit, `None` for any rising level
+    pub adaptive_threshold: Option<f64>,
+    pub quality: Quality,
+    /// Record the internal envelopes, see `Shaper::envelopes()`
+    pub inspector: bool,
+}
+
+
+/// The internal envelopes of the shaper at one sample. The detector levels
+/// are linear amplitudes, the pregain is the linear gain before the boost
+/// and the shape are applied.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Envelopes {
+    pub attack_fast: f32,
+    pub attack_slow: f32,
+    pub release_fast: f32,
+    pub release_slow: f32,
+    pub beat_level: f32,
+    pub pregain: f32,
+}
+
+
+/// The state the shaper changed to
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Transition {
+    Attack,
+    Release,
+    Idle
+}
+
+/// The maximum number of transitions recorded in one block. Further ones
+/// are dropped.
+pub const MAX_TRANSITIONS: usize = 64;
+
+/// The transitions in the current block in the order they happened, each
+/// with the sample index at which the state changed. The list has a fixed
+/// size, so that it can be filled in `run()`.
+#[derive(Clone, Copy, Debug)]
+pub struct Transitions {
+    events: [(usize, Transition); MAX_TRANSITIONS],
+    len: usize,
+}
+
+impl Default for Transitions {
+    fn default() -> Self {
+        Transitions {
+            events: [(0, Transition::Idle); MAX_TRANSITIONS],
+            len: 0,
+        }
+    }
+}
+
+impl Transitions {
+    pub fn push(&mut self, point: usize, transition: Transition) {
+        if self.len < MAX_TRANSITIONS {
+            self.events[self.len] = (point, transition);
+            self.len += 1;
+        }
+    }
+
+    pub fn events(&self) -> &[(usize, Transition)] {
+        &self.events[..self.len]
+    }
+}
+
+#[cfg(test)]
+impl Transitions {
+    /// The transitions of a block with the given events
+    pub(crate) fn from_events(events: &[(usize, Transition)]) -> Self {
+        let mut transitions = Transitions::default();
+        for &(point, transition) in events {
+            transitions.push(point, transition);
+        }
+        transitions
+    }
+}
+
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum State {
+    Attack,
+    Release,
+    Idle,
+    Disabled
+}
+
+use State::*;
+
+
+/// The actual transient shaper
+///
+/// A block is processed in stages, each one running over the whole
+/// block. First the key level is detected, then the beat detector finds
+/// the rising edges. Then the gain is calculated in segments of samples
+/// that share the same state. Finally the gain is applied to all the
+/// channels.
+pub struct Shaper {
+    sample_rate: f64,
+
+    input_peaks: Vec<InterSamplePeak>,
+    sidechain_peaks: Vec<InterSamplePeak>,
+
+    beat_detector: BeatDetector,
+    level_average: LevelAverage,
+    above_threshold: bool,
+    spectral_flux: SpectralFlux,
+
+    attack_smooth: EnvelopeDetector,
+    sustain_smooth: EnvelopeDetector,
+
+    attack_slow: EnvelopeDetector,
+    attack_fast: EnvelopeDetector,
+
+    release_slow: EnvelopeDetector,
+    release_fast: EnvelopeDetector,
+
+    attack_boost: Dezipper,
+    sustain_boost: Dezipper,
+
+    attack_width: Dezipper,
+    sustain_width: Dezipper,
+    side_smooth: EnvelopeDetector,
+    /// The difference of the gain to unity after a hit has ended on a step
+    /// of the beat grid that is not set
+    grid_fade: EnvelopeDetector,
+
+    attack_tone: Dezipper,
+    sustain_tone: Dezipper,
+    crossovers: Vec<Crossover>,
+
+    attack_drive: Dezipper,
+
+    lookahead: usize,
+    delays: Vec<Delay>,
+    /// The level is delayed by the lag of the onset detector, so that the
+    /// envelopes follow the hit the onset has been reported for.
+    onset_lag: usize,
+    level_delay: Delay,
+
+    attack_shape: f64,
+    sustain_shape: f64,
+
+    result_gain: EnvelopeDetector,
+
+    outgain: Dezipper,
+    mix: Dezipper,
+
+    sustain_count: usize,
+    attack_velocity: f64,
+    /// The frame of the audio the current hit started at, relative to the
+    /// current block
+    hit_frame: i64,
+
+    inspector: bool,
+
+    level_buffer: Vec<f32>,
+    rising_buffer: Vec<bool>,
+    velocity_buffer: Vec<f32>,
+    max_level_buffer: Vec<f64>,
+    beat_level_buffer: Vec<f32>,
+    threshold_buffer: Vec<f32>,
+    gain_buffer: Vec<f32>,
+    side_buffer: Vec<f32>,
+    tone_buffer: Vec<f32>,
+    drive_buffer: Vec<f32>,
+    apply_buffer: Vec<ApplyGains>,
+    factor_buffer: Vec<f32>,
+    driven_buffers: Vec<Vec<f32>>,
+    envelope_buffer: Vec<Envelopes>,
+
+    state: State,
+}
+
+impl Shaper {
+    pub fn new(sample_rate: f64,
+               max_block_length: usize,
+               channels: usize,
+               sidechain_channels: usize) -> Self {
+        let max_lookahead = Iterator::max([REALTIME_QUALITY, OFFLINE_QUALITY]
+            .iter()
+            .map(|quality| quality.lookahead_frames(sample_rate)))
+            .unwrap_or(0);
+        let spectral_flux = SpectralFlux::new(sample_rate);
+        let max_lookahead = max_lookahead + spectral_flux.latency();
+
+        Shaper {
+            sample_rate,
+
+            input_peaks: (0..channels).map(|_| InterSamplePeak::new()).collect(),
+            sidechain_peaks: (0..sidechain_channels).map(|_| InterSamplePeak::new()).collect(),
+
+            beat_detector: BeatDetector::new(sample_rate, 0.2),
+            level_average: LevelAverage::new(sample_rate, 2.0),
+            above_threshold: false,
+            spectral_flux,
+
+            attack_smooth: EnvelopeDetector::new(sample_rate),
+            sustain_smooth: EnvelopeDetector::new(sample_rate),
+
+            attack_slow: EnvelopeDetector::new(sample_rate),
+            attack_fast: EnvelopeDetector::new(sample_rate),
+
+            release_slow: EnvelopeDetector::new(sample_rate),
+            release_fast: EnvelopeDetector::new(sample_rate),
+
+            attack_boost: Dezipper::new(0.0, sample_rate),
+            sustain_boost: Dezipper::new(0.0, sample_rate),
+
+            attack_width: Dezipper::new(0.0, sample_rate),
+            sustain_width: Dezipper::new(0.0, sample_rate),
+            side_smooth: EnvelopeDetector::new(sample_rate),
+            grid_fade: EnvelopeDetector::new(sample_rate),
+
+            attack_tone: Dezipper::new(0.0, sample_rate),
+            sustain_tone: Dezipper::new(0.0, sample_rate),
+            crossovers: (0..channels).map(|_| Crossover::new(sample_rate)).collect(),
+
+            attack_drive: Dezipper::new(0.0, sample_rate),
+
+            lookahead: 0,
+            delays: (0..channels)
+                .map(|_| Delay::new(DELAY_HISTORY + max_lookahead + max_block_length))
+                .collect(),
+            onset_lag: 0,
+            level_delay: Delay::new(DELAY_HISTORY + max_lookahead + max_block_length),
+
+            attack_shape: 1.0,
+            sustain_shape: 1.0,
+
+            result_gain: EnvelopeDetector::new(sample_rate),
+
+            outgain: Dezipper::new(1.0, sample_rate),
+            mix: Dezipper::new(1.0, sample_rate),
+
+            sustain_count: 0,
+            attack_velocity: 1.0,
+            hit_frame: 0,
+
+            inspector: false,
+
+            level_buffer: Vec::with_capacity(max_block_length),
+            rising_buffer: Vec::with_capacity(max_block_length),
+            velocity_buffer: Vec::with_capacity(max_block_length),
+            max_level_buffer: Vec::with_capacity(max_block_length),
+            beat_level_buffer: Vec::with_capacity(max_block_length),
+            threshold_buffer: Vec::with_capacity(max_block_length),
+            gain_buffer: Vec::with_capacity(max_block_length),
+            side_buffer: Vec::with_capacity(max_block_length),
+            tone_buffer: Vec::with_capacity(max_block_length),
+            drive_buffer: Vec::with_capacity(max_block_length),
+            apply_buffer: Vec::with_capacity(max_block_length),
+            factor_buffer: Vec::with_capacity(max_block_length),
+            driven_buffers: (0..channels).map(|_| Vec::with_capacity(max_block_length)).collect(),
+            envelope_buffer: Vec::with_capacity(max_block_length),
+
+            state: Idle,
+        }
+    }
+
+    /// The gain applied to the samples of the last block (before output
+    /// level and dry/wet mix).
+    pub fn gain_signal(&self) -> &[f32] {
+        &self.gain_buffer
+    }
+
+    /// The adaptive threshold in dB during the last block, empty if the
+    /// adaptive threshold is not used.
+    pub fn threshold_signal(&self) -> &[f32] {
+        &self.threshold_buffer
+    }
+
+    /// The internal envelopes during the last block, empty if the inspector
+    /// is off.
+    pub fn envelopes(&self) -> &[Envelopes] {
+        &self.envelope_buffer
+    }
+
+    /// The frames the output is delayed by the lookahead and the lag of the
+    /// onset detector
+    pub fn latency(&self) -> usize {
+        self.lookahead
+    }
+
+    /// Processes a block. `midi_triggers` are only looked at if the
+    /// trigger source is MIDI.
+    pub fn process(&mut self,
+                   params: &Parameters,
+                   transport: &Transport,
+                   midi_triggers: &[Trigger],
+                   input: &[&[f32]],
+                   sidechain: &[&[f32]],
+                   output: &mut [&mut [f32]]) -> Transitions {
+        self.set_parameters(params);
+
+        let (key, weights) = if params.use_sidechain {
+            (sidechain, &UNITY_WEIGHTS)
+        } else {
+            (input, &params.key_weights)
+        };
+        self.detect_level(key, weights, params.use_sidechain, params.quality);
+        if self.onset_lag > 0 {
+            self.delay_level();
+        }
+        self.detect_beats(params.adaptive_threshold);
+        if params.onset_detector == OnsetDetector::SpectralFlux {
+            self.detect_onsets(key, weights);
+        }
+        if params.trigger_source == TriggerSource::Midi {
+            self.place_midi_triggers(midi_triggers);
+        }
+
+        let transitions = self.calc_gain(params, transport);
+
+        // The delay lines and the buffers are taken out while the gain is
+        // applied, which does not allocate.
+        let mut delays = mem::take(&mut self.delays);
+        let mut driven_buffers = mem::take(&mut self.driven_buffers);
+        {
+            let n_samples = self.level_buffer.len();
+            let mut delayed: [&[f32]; MAX_CHANNELS] = [&[]; MAX_CHANNELS];
+            let input = if self.lookahead > 0 {
+                for (delay, signal) in Iterator::zip(delays.iter_mut(), input.iter()) {
+                    delay.push(signal);
+                }
+                for (delayed, delay) in Iterator::zip(delayed.iter_mut(), delays.iter()) {
+                    *delayed = delay.delayed(n_samples);
+                }
+                &delayed[..input.len()]
+            } else {
+                input
+            };
+
+            let mut driven: [&[f32]; MAX_CHANNELS] = [&[]; MAX_CHANNELS];
+            let driven = if self.drive_buffer.iter().any(|&drive| drive > 1.0) {
+                self.drive(params.saturation, params.quality, input, &delays, &mut driven_buffers);
+                for (driven, buffer) in Iterator::zip(driven.iter_mut(), driven_buffers.iter()) {
+                    *driven = buffer;
+                }
+                &driven[..input.len()]
+            } else {
+                input
+            };
+
+            self.apply_gain(input, driven, output);
+        }
+        self.delays = delays;
+        self.driven_buffers = driven_buffers;
+
+        transitions
+    }
+
+    fn set_parameters(&mut self, params: &Parameters) {
+        self.attack_fast.set_params(0.0, 0.02);
+        self.attack_slow.set_params(0.02, 5.0);
+
+        self.release_fast.set_params(0.01, 0.02);
+        self.release_slow.set_params(0.02, 0.025);
+
+        self.attack_boost.set_value(params.attack_boost);
+        self.sustain_boost.set_value(params.sustain_boost);
+
+        self.attack_smooth.set_params(0.0, params.attack_smooth);
+        self.sustain_smooth.set_params(params.sustain_smooth, params.sustain_smooth);
+        self.side_smooth.set_params(params.sustain_smooth, params.sustain_smooth);
+        self.grid_fade.set_params(GRID_FADE, GRID_FADE);
+
+        self.attack_width.set_value(params.attack_width);
+        self.sustain_width.set_value(params.sustain_width);
+
+        self.attack_tone.set_value(params.attack_tone);
+        self.sustain_tone.set_value(params.sustain_tone);
+
+        self.attack_drive.set_value(params.attack_drive);
+
+        // the onsets are reported late, so the level and the audio have to
+        // wait for them
+        let onset_lag = if params.onset_detector == OnsetDetector::SpectralFlux {
+            self.spectral_flux.latency()
+        } else {
+            0
+        };
+        if onset_lag != self.onset_lag {
+            self.onset_lag = onset_lag;
+            self.level_delay.set_length(onset_lag);
+        }
+        let lookahead = params.quality.lookahead_frames(self.sample_rate) + onset_lag;
+        if lookahead != self.lookahead {
+            self.lookahead = lookahead;
+            for delay in self.delays.iter_mut() {
+                delay.set_length(lookahead);
+            }
+        }
+
+        self.attack_shape = shape_exponent(params.attack_shape);
+        self.sustain_shape = shape_exponent(params.sustain_shape);
+
+        self.inspector = params.inspector;
+
+        self.outgain.set_value(from_dB(params.outgain));
+
+        if params.enabled {
+            self.mix.set_value(params.mix);
+            if self.state == Disabled {
+                self.state = Idle;
+            }
+        } else {
+            self.mix.set_value(0.0);
+            self.state = Disabled;
+        }
+    }
+
+    fn detect_level(&mut self,
+                    signals: &[&[f32]],
+                    weights: &[f32],
+                    sidechain: bool,
+                    quality: Quality) {
+        let n_samples = signals[0].len();
+        self.level_buffer.clear();
+        self.level_buffer.resize(n_samples, 0.0);
+
+        let peaks = if sidechain {
+            &mut self.sidechain_peaks[..]
+        } else {
+            &mut self.input_peaks[..]
+        };
+
+        let oversampling = quality.detector_oversampling;
+        let channels = Iterator::zip(signals.iter(), weights.iter());
+        for ((signal, &weight), peak) in Iterator::zip(channels, peaks.iter_mut()) {
+            if oversampling > 1 {
+                for (level, &v) in Iterator::zip(self.level_buffer.iter_mut(), signal.iter()) {
+                    *level = level.max(peak.process(v, oversampling) * weight);
+                }
+            } else {
+                for (level, &v) in Iterator::zip(self.level_buffer.iter_mut(), signal.iter()) {
+                    *level = level.max(v.abs() * weight);
+                }
+                peak.track(signal);
+            }
+        }
+    }
+
+    fn delay_level(&mut self) {
+        let n_samples = self.level_buffer.len();
+        self.level_delay.push(&self.level_buffer);
+        self.level_buffer.copy_from_slice(self.level_delay.delayed(n_samples));
+    }
+
+    fn detect_beats(&mut self, adaptive_threshold: Option<f64>) {
+        self.rising_buffer.clear();
+        self.velocity_buffer.clear();
+        self.max_level_buffer.clear();
+        self.beat_level_buffer.clear();
+        self.threshold_buffer.clear();
+
+        for &lvl in self.level_buffer.iter() {
+            let old_lvl = self.beat_detector.level();
+            let beat_detect = self.beat_detector.process(lvl as f64);
+            let rising = if let Some(margin) = adaptive_threshold {
+                // A hit starts when the level crosses the threshold. It needs
+                // to fall below it again before the next hit can start.
+                let threshold = self.level_average.process(beat_detect) + margin;
+                self.threshold_buffer.push(threshold as f32);
+                let was_above = self.above_threshold;
+                self.above_threshold = beat_detect > from_dB(threshold);
+                self.above_threshold && !was_above
+            } else {
+                beat_detect > old_lvl
+            };
+            self.rising_buffer.push(rising);
+            self.velocity_buffer.push(1.0);
+            self.max_level_buffer.push(self.beat_detector.max_level());
+            self.beat_level_buffer.push(beat_detect as f32);
+        }
+    }
+
+    fn detect_onsets(&mut self, signals: &[&[f32]], weights: &[f32]) {
+        // The beat detector's rising edges are replaced, but we still need
+        // its maximum level to calculate the attack gain.
+        for (i, rising) in self.rising_buffer.iter_mut().enumerate() {
+            let v = Iterator::zip(signals.iter(), weights.iter())
+                .map(|(signal, &weight)| signal[i] * weight)
+                .sum();
+            *rising = self.spectral_flux.process(v);
+        }
+    }
+
+    fn place_midi_triggers(&mut self, midi_triggers: &[Trigger]) {
+        // The beat detector still runs in MIDI mode as we need its maximum
+        // level to calculate the attack gain.
+        for rising in self.rising_buffer.iter_mut() {
+            *rising = false;
+        }
+        for trigger in midi_triggers {
+            if trigger.frame < self.rising_buffer.len() {
+                self.rising_buffer[trigger.frame] = true;
+                self.velocity_buffer[trigger.frame] = trigger.velocity;
+            }
+        }
+    }
+
+    fn calc_gain(&mut self, params: &Parameters, transport: &Transport) -> Transitions {
+        let mut transitions = Transitions::default();
+        let n_samples = self.level_buffer.len();
+
+        self.gain_buffer.clear();
+        self.side_buffer.clear();
+        self.tone_buffer.clear();
+        self.drive_buffer.clear();
+        self.envelope_buffer.clear();
+
+        let mut i = 0;
+        while i < n_samples {
+            i = match self.state {
+                Idle | Disabled => self.idle_segment(i, params, transport, &mut transitions),
+                Attack => self.attack_segment(i, params, transport, &mut transitions),
+                Release => self.release_segment(i, params, transport, &mut transitions),
+            };
+        }
+        self.hit_frame -= n_samples as i64;
+
+        transitions
+    }
+
+    fn triggers(&self, i: usize, params: &Parameters, transport: &Transport) -> bool {
+        // the level has been delayed by the onset lag
+        let frame = i as i64 - self.onset_lag as i64;
+        self.rising_buffer[i] && !self.masked_at(frame, params, transport)
+    }
+
+    /// Whether the current hit is to end at frame `i`. The gain of frame `i`
+    /// is applied to the audio that came in the latency before, so the hit
+    /// ends when that audio is on a masked step. While the gain is rising
+    /// ahead of the hit, the audio of the hit counts.
+    fn masked(&self, i: usize, params: &Parameters, transport: &Transport) -> bool {
+        let frame = (i as i64 - self.lookahead as i64).max(self.hit_frame);
+        self.masked_at(frame, params, transport)
+    }
+
+    fn masked_at(&self, frame: i64, params: &Parameters, transport: &Transport) -> bool {
+        params.beat_grid.map_or(false, |grid| !grid.is_open(transport, frame))
+    }
+
+    /// Ends a hit on a step of the beat grid that is not set. The gain fades
+    /// back to unity from where it is within `GRID_FADE`.
+    fn end_masked_hit(&mut self, i: usize, transitions: &mut Transitions) {
+        self.state = Idle;
+        transitions.push(i, Transition::Idle);
+        self.grid_fade.reset(self.result_gain.level() - 1.0);
+        self.sustain_smooth.reset(1.0);
+        self.side_smooth.reset(1.0);
+    }
+
+    fn inspect(&mut self, i: usize, pregain: f64) {
+        if self.inspector {
+            self.envelope_buffer.push(Envelopes {
+                attack_fast: self.attack_fast.level() as f32,
+                attack_slow: self.attack_slow.level() as f32,
+                release_fast: self.release_fast.level() as f32,
+                release_slow: self.release_slow.level() as f32,
+                beat_level: self.beat_level_buffer[i],
+                pregain: pregain as f32,
+            });
+        }
+    }
+
+    fn start_attack(&mut self, i: usize, transitions: &mut Transitions) {
+        self.attack_fast.reset(0.0);
+        self.attack_slow.reset(0.0);
+        self.attack_smooth.reset(self.result_gain.level());
+        self.attack_velocity = self.velocity_buffer[i] as f64;
+        self.hit_frame = i as i64 - self.onset_lag as i64;
+        self.grid_fade.reset(0.0);
+        transitions.push(i, Transition::Attack);
+        self.state = Attack;
+    }
+
+    // The segment functions process samples from `start` on as long as the
+    // state does not change. They return the index of the first sample that
+    // they did not process.
+
+    fn idle_segment(&mut self,
+                    start: usize,
+                    params: &Parameters,
+                    transport: &Transport,
+                    transitions: &mut Transitions) -> usize {
+        let n_samples = self.level_buffer.len();
+        let mut i = start;
+        while i < n_samples {
+            if self.state != Disabled && self.triggers(i, params, transport) {
+                self.start_attack(i, transitions);
+                break;
+            }
+            let gain = self.sustain_smooth.process(1.0) + self.grid_fade.process(0.0);
+            let gain = self.result_gain.process(gain);
+            self.gain_buffer.push(gain as f32);
+            self.side_buffer.push(self.side_smooth.process(1.0) as f32);
+            // the gain might still be fading out from the sustain
+            self.tone_buffer.push(self.sustain_tone.process() as f32);
+            self.drive_buffer.push(1.0);
+            self.inspect(i, 1.0);
+            i += 1;
+        }
+
+        self.attack_boost.skip(i - start);
+        self.sustain_boost.skip(i - start);
+        self.attack_width.skip(i - start);
+        self.sustain_width.skip(i - start);
+        self.attack_tone.skip(i - start);
+        self.attack_drive.skip(i - start);
+        i
+    }
+
+    fn attack_segment(&mut self,
+                      start: usize,
+                      params: &Parameters,
+                      transport: &Transport,
+                      transitions: &mut Transitions) -> usize {
+        let n_samples = self.level_buffer.len();
+        let mut i = start;
+        while i < n_samples {
+            if self.masked(i, params, transport) {
+                self.end_masked_hit(i, transitions);
+                break;
+            }
+
+            // A rising level in attack state just keeps us in attack state.
+            let lvl = self.level_buffer[i] as f64;
+            let attack_boost = self.attack_boost.process() * self.attack_velocity;
+
+            let atk_fast = self.attack_fast.process(lvl);
+            let atk_slow = self.attack_slow.process(lvl);
+            let delta_atk = atk_fast - atk_slow;
+
+            // an onset can be reported before the level rises
+            let max_level = self.max_level_buffer[i];
+            let delta_atk = if max_level > 0.0 { delta_atk / max_level } else { 0.0 };
+            let pregain = self.attack_smooth.process(from_dB(delta_atk));
+
+            let gain = self.result_gain.process(shape_curve(pregain, self.attack_shape).powf(attack_boost));
+            self.gain_buffer.push(gain as f32);
+            let side = side_gain(pregain, self.attack_width.process());
+            self.side_buffer.push(side as f32);
+            self.tone_buffer.push(self.attack_tone.process() as f32);
+            let attack_drive = self.attack_drive.process();
+            let drive = if attack_drive == 0.0 {
+                1.0
+            } else {
+                pregain.max(1.0).powf(attack_drive)
+            };
+            self.drive_buffer.push(drive as f32);
+            self.inspect(i, pregain);
+
+            if pregain < 1.0 {
+                self.state = Release;
+                transitions.push(i, Transition::Release);
+                self.release_fast.reset(atk_slow);
+                self.release_slow.reset(0.0);
+                self.sustain_smooth.reset(pregain);
+                self.sustain_count = 0;
+                i += 1;
+                break;
+            }
+            i += 1;
+        }
+
+        self.sustain_boost.skip(i - start);
+        self.sustain_width.skip(i - start);
+        self.sustain_tone.skip(i - start);
+        i
+    }
+
+    fn release_segment(&mut self,
+                       start: usize,
+                       params: &Parameters,
+                       transport: &Transport,
+                       transitions: &mut Transitions) -> usize {
+        // voodoo to compensate smoothening
+        let compensation = (15.0 + 3.0 * params.sustain_smooth.log10()) / 7.0;
+
+        let n_samples = self.level_buffer.len();
+        let mut i = start;
+        while i < n_samples {
+            if self.triggers(i, params, transport) {
+                self.start_attack(i, transitions);
+                break;
+            }
+            if self.masked(i, params, transport) {
+                self.end_masked_hit(i, transitions);
+                break;
+            }
+
+            let lvl = self.level_buffer[i] as f64;
+            let sustain_boost = self.sustain_boost.process();
+            let sustain_width = self.sustain_width.process();
+
+            let rel_fast = self.release_fast.process(lvl);
+            let rel_slow = self.release_slow.process(lvl);
+
+            let delta_rel = rel_fast - rel_slow;
+            let pregain = self.sustain_smooth.process(
+                from_dB(delta_rel / self.attack_slow.level() * compensation)
+            );
+
+            let (gain, side) = if let Some(length) = params.sustain_length {
+                let gain = shape_curve(pregain.max(1.0), self.sustain_shape).powf(sustain_boost);
+                let side = side_gain(pregain.max(1.0), sustain_width);
+                self.sustain_count += 1;
+                if self.sustain_count >= length {
+                    self.state = Idle;
+                    // fade back to unity from where we are to avoid a click
+                    self.sustain_smooth.reset(gain);
+                    self.side_smooth.reset(side);
+                }
+                (gain, side)
+            } else {
+                if pregain < 1.0 {
+                    self.state = Idle;
+                    self.side_smooth.reset(1.0);
+                }
+                (shape_curve(pregain, self.sustain_shape).powf(sustain_boost),
+                 side_gain(pregain, sustain_width))
+            };
+
+            self.gain_buffer.push(self.result_gain.process(gain) as f32);
+            self.side_buffer.push(side as f32);
+            self.tone_buffer.push(self.sustain_tone.process() as f32);
+            self.drive_buffer.push(1.0);
+            self.inspect(i, pregain);
+            i += 1;
+
+            if self.state == Idle {
+                transitions.push(i - 1, Transition::Idle);
+                break;
+            }
+        }
+
+        self.attack_boost.skip(i - start);
+        self.attack_width.skip(i - start);
+        self.attack_tone.skip(i - start);
+        self.attack_drive.skip(i - start);
+        i
+    }
+
+    /// Drives the input into the saturation. Oversampling needs the
+    /// samples after the current one, so it is only done with lookahead.
+    fn drive(&self,
+             saturation: Saturation,
+             quality: Quality,
+             input: &[&[f32]],
+             delays: &[Delay],
+             driven_buffers: &mut [Vec<f32>]) {
+        let oversampling = if self.lookahead >= DELAY_HISTORY {
+            quality.gain_oversampling
+        } else {
+            1
+        };
+        let channels = Iterator::zip(input.iter(), delays.iter());
+        for ((input, delay), driven) in Iterator::zip(channels, driven_buffers.iter_mut()) {
+            driven.clear();
+            for (i, (&v, &drive)) in Iterator::zip(input.iter(), self.drive_buffer.iter()).enumerate() {
+                driven.push(if drive <= 1.0 {
+                    v
+                } else if oversampling > 1 {
+                    saturation.process_oversampled(delay.window(i), drive, oversampling)
+                } else {
+                    saturation.process(v, drive)
+                });
+            }
+        }
+    }
+
+    /// Applies the gain to `input`, or to `driven` where it has been driven
+    /// into the saturation.
+    fn apply_gain(&mut self,
+                  input: &[&[f32]],
+                  driven: &[&[f32]],
+                  output: &mut [&mut [f32]]) {
+        // The crossovers are only needed while a tone is set. When the tone
+        // is faded in, the low and the high gain are still equal, so the
+        // stale crossover state does not matter.
+        let tone = self.tone_buffer.iter().any(|&t| t != 0.0);
+
+        // Without tone, saturation and width the gain, output level and
+        // dry/wet mix are just one factor per sample.
+        let stereo = input.len() == 2;
+        let neutral = !tone
+            && self.drive_buffer.iter().all(|&drive| drive <= 1.0)
+            && (!stereo || self.side_buffer.iter().all(|&side| side == 1.0));
+        if neutral {
+            self.factor_buffer.clear();
+            for &gain in self.gain_buffer.iter() {
+                let mix = self.mix.process();
+                let outgain = self.outgain.process();
+                self.factor_buffer.push((gain as f64 * outgain * mix + 1.0 - mix) as f32);
+            }
+            for (input, output) in Iterator::zip(input.iter(), output.iter_mut()) {
+                let samples = Iterator::zip(input.iter(), self.factor_buffer.iter());
+                for (out, (&v, &factor)) in Iterator::zip(output.iter_mut(), samples) {
+                    *out = v * factor;
+                }
+            }
+            return;
+        }
+
+        // output level and dry/wet mix folded into the factors per sample
+        self.apply_buffer.clear();
+        let gains = Iterator::zip(self.gain_buffer.iter(), self.side_buffer.iter());
+        for ((&gain, &side), &t) in Iterator::zip(gains, self.tone_buffer.iter()) {
+            let mix = self.mix.process();
+            let outgain = self.outgain.process();
+            let gain = gain as f64;
+            let wet = gain * outgain * mix;
+            let (low, high) = if tone {
+                let t = t as f64;
+                (outgain * mix * gain.powf(1.0 - t), outgain * mix * gain.powf(1.0 + t))
+            } else {
+                (wet, wet)
+            };
+            self.apply_buffer.push(ApplyGains {
+                low: low as f32,
+                high: high as f32,
+                side: (wet * (side as f64 - 1.0)) as f32,
+                dry: (1.0 - mix) as f32,
+            });
+        }
+
+        if let ([left_in, right_in], [left_driven, right_driven], [left_out, right_out])
+            = (input, driven, &mut *output) {
+            if let [left_crossover, right_crossover] = &mut self.crossovers[..] {
+                let samples = Iterator::zip(left_in.iter(), right_in.iter());
+                let driven = Iterator::zip(left_driven.iter(), right_driven.iter());
+                let outs = Iterator::zip(left_out.iter_mut(), right_out.iter_mut());
+                let frames = Iterator::zip(Iterator::zip(samples, driven), self.apply_buffer.iter());
+                for ((((&l, &r), (&l_driven, &r_driven)), gains), (l_out, r_out)) in Iterator::zip(frames, outs) {
+                    let side = gains.side * 0.5 * (l - r);
+                    *l_out = gains.shape(l, l_driven, left_crossover, tone) + side;
+                    *r_out = gains.shape(r, r_driven, right_crossover, tone) - side;
+                }
+                return;
+            }
+        }
+
+        let channels = Iterator::zip(Iterator::zip(input.iter(), driven.iter()), output.iter_mut());
+        for (((input, driven), output), crossover) in Iterator::zip(channels, self.crossovers.iter_mut()) {
+            let samples = Iterator::zip(Iterator::zip(input.iter(), driven.iter()), self.apply_buffer.iter());
+            for (out, ((&v, &driven), gains)) in Iterator::zip(output.iter_mut(), samples) {
+                *out = gains.shape(v, driven, crossover, tone);
+            }
+        }
+    }
+}
+
+/// Reshapes the detector curve `pregain` before the boost is applied, see
+/// `urids::shape::reshape()`. In dB the pregain is the normalized detector
+/// difference.
+fn shape_curve(pregain: f64, exponent: f64) -> f64 {
+    if exponent == 1.0 {
+        return pregain;
+    }
+    from_dB(reshape(20.0 * pregain.log10(), exponent))
+}
+
+/// The gain of the side signal relative to the mid signal. It follows the
+/// detector curve `pregain` like the boost does.
+fn side_gain(pregain: f64, width: f64) -> f64 {
+    if width == 0.0 {
+        1.0
+    } else {
+        pregain.powf(width)
+    }
+}
+
+#[allow(non_snake_case)]
+fn from_dB(v: f64) -> f64 {
+    (v * LN_10 / 20.0).exp()
+}
+
+#[allow(non_snake_case)]
+pub(crate) fn to_dB(v: &f32) -> f32 {
+    20.0f32 * f32::log10(v.abs().max(1e-8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use urids::shape::SHAPE_RANGE;
+
+    const SHAPES: [f64; 5] = [-1.0, -0.5, 0.0, 0.5, 1.0];
+
+    fn parameters(quality: Quality) -> Parameters {
+        Parameters {
+            enabled: true,
+            use_sidechain: false,
+            key_weights: [1.0; MAX_CHANNELS],
+            attack_boost: 0.0,
+            attack_smooth: 0.035,
+            sustain_boost: 0.0,
+            sustain_smooth: 0.035,
+            attack_width: 0.0,
+            sustain_width: 0.0,
+            attack_tone: 0.0,
+            sustain_tone: 0.0,
+            attack_drive: 0.0,
+            saturation: Saturation::Tape,
+            attack_shape: 0.0,
+            sustain_shape: 0.0,
+            outgain: 0.0,
+            mix: 1.0,
+            sustain_length: None,
+            beat_grid: None,
+            trigger_source: TriggerSource::Audio,
+            onset_detector: OnsetDetector::Level,
+            adaptive_threshold: None,
+            quality,
+            inspector: false
+        }
+    }
+
+    /// Clicks every 1000 frames
+    fn click_signal(length: usize) -> Vec<f32> {
+        (0..length).map(|i| if i % 1000 == 10 { 0.8 } else { 0.01 }).collect()
+    }
+
+    fn process(shaper: &mut Shaper, params: &Parameters, input: &[f32]) -> Vec<f32> {
+        process_at(shaper, params, &Transport::new(48000.0), input).1
+    }
+
+    fn process_at(shaper: &mut Shaper,
+                  params: &Parameters,
+                  transport: &Transport,
+                  input: &[f32]) -> (Transitions, Vec<f32>) {
+        let mut output = vec![0.0; input.len()];
+        let transitions = shaper.process(params, transport, &[], &[input], &[input], &mut [&mut output[..]]);
+        (transitions, output)
+    }
+
+    /// Decaying hits at the beginning of the beats at 120 BPM, but the one
+    /// of the third beat comes a bit early
+    fn beat_signal() -> Vec<f32> {
+        let hit = |frame: usize, start: usize| if frame >= start {
+            let t = (frame - start) as f32 / 48000.0;
+            0.8 * (-t / 0.03).exp() * (2.0 * std::f32::consts::PI * 200.0 * t).cos()
+        } else {
+            0.0
+        };
+        (0..96000).map(|i| [10, 24010, 47990, 72010].iter().map(|&start| hit(i, start)).sum()).collect()
+    }
+
+    fn grid_parameters(mask: u32) -> Parameters {
+        Parameters {
+            attack_boost: 12.0,
+            sustain_boost: 6.0,
+            beat_grid: Some(BeatGrid { steps: 4, mask }),
+            ..parameters(REALTIME_QUALITY)
+        }
+    }
+
+    fn attack_points(transitions: &Transitions) -> Vec<usize> {
+        transitions.events().iter()
+            .filter(|&&(_, transition)| transition == Transition::Attack)
+            .map(|&(point, _)| point)
+            .collect()
+    }
+
+    #[test]
+    fn only_hits_on_open_steps_are_shaped() {
+        let input = beat_signal();
+        let mut shaper = Shaper::new(48000.0, input.len(), 1, 1);
+        let transport = Transport::at_position(48000.0, 120.0, 1.0, 0.0);
+        let (transitions, output) = process_at(&mut shaper, &grid_parameters(0b0010), &transport, &input);
+
+        assert_eq!(attack_points(&transitions), [24010, 47990]);
+        // the hit just before the third beat ends with it, give or take the
+        // rounding of the position
+        assert!(transitions.events().iter()
+                .any(|&(point, transition)| transition == Transition::Idle && (48000..=48001).contains(&point)));
+        // unity gain on the masked beats
+        for &frame in [20000, 72010, 74000].iter() {
+            assert!((output[frame] / input[frame] - 1.0).abs() < 1e-3, "{}", frame);
+        }
+        assert!(output[24010] / input[24010] > 1.5);
+    }
+
+    #[test]
+    fn hit_ends_at_masked_step() {
+        let input = beat_signal();
+        let transport = Transport::at_position(48000.0, 120.0, 1.0, 0.0);
+        let gain_after_step = |mask| {
+            let mut shaper = Shaper::new(48000.0, input.len(), 1, 1);
+            let (_, output) = process_at(&mut shaper, &grid_parameters(mask), &transport, &input);
+            output[48500] / input[48500]
+        };
+        // 10 ms into the third beat
+        assert!(gain_after_step(0b0110) > 2.0);
+        assert!((gain_after_step(0b0010) - 1.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn all_steps_open_while_stopped() {
+        let input = beat_signal();
+        let mut shaper = Shaper::new(48000.0, input.len(), 1, 1);
+        let transport = Transport::at_position(48000.0, 120.0, 0.0, 0.0);
+        let (transitions, _) = process_at(&mut shaper, &grid_parameters(0b0010), &transport, &input);
+        assert_eq!(attack_points(&transitions), [10, 24010, 47990, 72010]);
+    }
+
+    #[test]
+    fn steps_follow_the_delayed_audio() {
+        let input = beat_signal();
+        let mut shaper = Shaper::new(48000.0, input.len(), 1, 1);
+        let transport = Transport::at_position(48000.0, 120.0, 1.0, 0.0);
+        let params = Parameters {
+            quality: OFFLINE_QUALITY,
+            ..grid_parameters(0b0010)
+        };
+        let (transitions, output) = process_at(&mut shaper, &params, &transport, &input);
+        let latency = shaper.latency();
+        assert_eq!(latency, 96);
+
+        // The hits are looked at where they are in the input, even though
+        // the gain rises ahead of them. The oversampled detector may see
+        // them a frame later.
+        let attacks = attack_points(&transitions);
+        assert_eq!(attacks.len(), 2);
+        for (&attack, &hit) in Iterator::zip(attacks.iter(), [24010, 47990].iter()) {
+            assert!((hit..=hit + 1).contains(&attack), "hit at {}, attack at {}", hit, attack);
+        }
+        // The hit just before the third beat ends when the delayed audio
+        // gets there, so it is shaped.
+        assert!(transitions.events().iter()
+                .any(|&(point, transition)| transition == Transition::Idle
+                     && (48000 + latency..=48001 + latency).contains(&point)));
+        assert!(output[47995 + latency] / input[47995] > 1.5);
+        for &frame in [20000, 72010, 74000].iter() {
+            assert!((output[frame + latency] / input[frame] - 1.0).abs() < 1e-3, "{}", frame);
+        }
+    }
+
+    #[test]
+    fn delay_over_blocks() {
+        let mut delay = Delay::new(16);
+        delay.set_length(3);
+        delay.push(&[1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(delay.delayed(4), [0.0, 0.0, 0.0, 1.0]);
+        assert_eq!(delay.window(3), [0.0, 0.0, 1.0, 2.0, 3.0]);
+        delay.push(&[5.0, 6.0]);
+        assert_eq!(delay.delayed(2), [2.0, 3.0]);
+        assert_eq!(delay.window(0), [0.0, 1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(delay.window(1), [1.0, 2.0, 3.0, 4.0, 5.0]);
+    }
+
+    #[test]
+    fn oversampled_saturation_of_constant_signal() {
+        for &saturation in [Saturation::Tape, Saturation::Tube, Saturation::Hard].iter() {
+            for &v in [-0.9, -0.2, 0.0, 0.3, 1.2].iter() {
+                let oversampled = saturation.process_oversampled(&[v; 5], 4.0, 4);
+                assert!((oversampled - saturation.process(v, 4.0)).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn lookahead_is_reported_as_latency() {
+        let input = click_signal(4096);
+        let mut shaper = Shaper::new(48000.0, 4096, 1, 1);
+
+        let output = process(&mut shaper, &parameters(OFFLINE_QUALITY), &input);
+        let latency = shaper.latency();
+        assert_eq!(latency, 96);
+        assert!(output[..latency].iter().all(|&v| v == 0.0));
+        for (&out, &v) in Iterator::zip(output[latency..].iter(), input.iter()) {
+            assert!((out - v).abs() < 1e-6);
+        }
+
+        // back to real time without latency
+        let output = process(&mut shaper, &parameters(REALTIME_QUALITY), &input);
+        assert_eq!(shaper.latency(), 0);
+        for (&out, &v) in Iterator::zip(output.iter(), input.iter()) {
+            assert!((out - v).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn oversampled_saturation_over_blocks() {
+        let input = click_signal(4096);
+        let params = Parameters {
+            attack_boost: 12.0,
+            attack_drive: 12.0,
+            ..parameters(OFFLINE_QUALITY)
+        };
+        let mut shaper = Shaper::new(48000.0, 4096, 1, 1);
+        let whole = process(&mut shaper, &params, &input);
+
+        let mut shaper = Shaper::new(48000.0, 64, 1, 1);
+        let blocks: Vec<f32> = input.chunks(64).flat_map(|block| process(&mut shaper, &params, block)).collect();
+
+        for (&a, &b) in Iterator::zip(whole.iter(), blocks.iter()) {
+            assert!((a - b).abs() < 1e-5, "{} {}", a, b);
+        }
+    }
+
+    #[test]
+    fn lookahead_boosts_ahead_of_the_hit() {
+        let input = click_signal(4096);
+        let params = Parameters {
+            attack_boost: 12.0,
+            ..parameters(OFFLINE_QUALITY)
+        };
+        let mut shaper = Shaper::new(48000.0, 4096, 1, 1);
+        let output = process(&mut shaper, &params, &input);
+
+        // the first click is at frame 10 of the input
+        let latency = shaper.latency();
+        let gain_at_click = output[10 + latency] / input[10];
+
+        let params = Parameters {
+            quality: REALTIME_QUALITY,
+            ..params
+        };
+        let mut shaper = Shaper::new(48000.0, 4096, 1, 1);
+        let output = process(&mut shaper, &params, &input);
+        let realtime_gain_at_click = output[10] / input[10];
+
+        assert!(gain_at_click > realtime_gain_at_click, "{} {}", gain_at_click, realtime_gain_at_click);
+    }
+
+    #[test]
+    fn onset_lag_is_reported_as_latency() {
+        let input = beat_signal();
+        let params = Parameters {
+            onset_detector: OnsetDetector::SpectralFlux,
+            ..parameters(REALTIME_QUALITY)
+        };
+        let mut shaper = Shaper::new(48000.0, input.len(), 1, 1);
+        let (transitions, _) = process_at(&mut shaper, &params, &Transport::new(48000.0), &input);
+        let latency = shaper.latency();
+        assert_eq!(latency, 160);
+
+        // the hits arrive at the output delayed by the latency, and the
+        // attack starts no later
+        let attacks = attack_points(&transitions);
+        assert_eq!(attacks.len(), 4);
+        for (&attack, &hit) in Iterator::zip(attacks.iter(), [10, 24010, 47990, 72010].iter()) {
+            assert!(attack >= hit && attack <= hit + latency, "hit at {}, attack at {}", hit, attack);
+        }
+
+        let params = Parameters {
+            onset_detector: OnsetDetector::Level,
+            ..params
+        };
+        process(&mut shaper, &params, &input[..64]);
+        assert_eq!(shaper.latency(), 0);
+    }
+
+    #[test]
+    fn gain_peaks_at_the_delayed_onset() {
+        let input = beat_signal();
+        let params = Parameters {
+            attack_boost: 12.0,
+            onset_detector: OnsetDetector::SpectralFlux,
+            ..parameters(REALTIME_QUALITY)
+        };
+        let mut shaper = Shaper::new(48000.0, input.len(), 1, 1);
+        let (transitions, output) = process_at(&mut shaper, &params, &Transport::new(48000.0), &input);
+        let latency = shaper.latency();
+        let gain = shaper.gain_signal();
+
+        // the first hit is still in the fade in
+        let attacks = attack_points(&transitions);
+        for (&attack, &hit) in Iterator::zip(attacks[1..].iter(), [24010, 47990, 72010].iter()) {
+            let delayed_hit = hit + latency;
+            let peak = (attack..attack + 2000)
+                .max_by(|&a, &b| gain[a].partial_cmp(&gain[b]).unwrap())
+                .unwrap();
+            assert!(peak >= delayed_hit && peak <= delayed_hit + 5, "hit at {}, peak at {}", delayed_hit, peak);
+            // the attack has waited for the transient
+            assert!(gain[delayed_hit - 1] < 1.01);
+            assert!(output[delayed_hit] / input[hit] > 3.0);
+        }
+    }
+
+    #[test]
+    fn shape_zero_is_identity() {
+        for &db in [-3.0, -0.5, 0.0, 0.25, 0.9, 1.5, 12.0].iter() {
+            let pregain = from_dB(db);
+            assert_eq!(shape_curve(pregain, shape_exponent(0.0)), pregain);
+        }
+    }
+
+    #[test]
+    fn gain_is_monotonic_in_shape() {
+        for &db in [0.1, 0.5, 0.9].iter() {
+            let gains: Vec<f64> = SHAPES.iter()
+                .map(|&shape| shape_curve(from_dB(db), shape_exponent(shape)))
+                .collect();
+            assert!(gains.windows(2).all(|pair| pair[0] < pair[1]), "{} dB: {:?}", db, gains);
+        }
+    }
+
+    #[test]
+    fn shape_is_bounded_beyond_range() {
+        for &shape in SHAPES.iter() {
+            let exponent = shape_exponent(shape);
+            for &db in [1.0, 1.84, 6.0, -2.0].iter() {
+                let pregain = from_dB(db);
+                assert!((shape_curve(pregain, exponent) - pregain).abs() < 1e-12);
+            }
+            // continuous at the end of the range
+            let below = shape_curve(from_dB(SHAPE_RANGE - 1e-9), exponent);
+            assert!((below - from_dB(SHAPE_RANGE)).abs() < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use crate::dsp::{Transition, Transitions};
+
+// The learn mode records the lengths of the attack and the sustain part of
+// the last hits. The statistics are then analysed by the worker, which
+// suggests smooth settings for the material.
+
+/// The number of hits the suggestion is based on
+pub const MAX_HITS: usize = 32;
+
+/// We don't suggest anything before we have seen that many hits.
+pub const MIN_HITS: usize = 4;
+
+/// The lengths in frames of the recorded hits. This is sent to the worker,
+/// so it must not own any heap memory.
+#[derive(Clone, Copy)]
+pub struct HitStatistics {
+    pub attack_lengths: [u32; MAX_HITS],
+    pub sustain_lengths: [u32; MAX_HITS],
+    pub count: usize,
+    pub sample_rate: f32,
+}
+
+/// Smooth settings suggested from the recorded hits
+#[derive(Clone, Copy, Debug)]
+pub struct Suggestion {
+    pub attack_smooth: f32,
+    pub sustain_smooth: f32,
+}
+
+/// Follows the transitions of the shaper over the blocks.
+pub struct HitRecorder {
+    frame: u64,
+    attack: Option<u64>,
+    release: Option<u64>,
+
+    statistics: HitStatistics,
+    next: usize,
+}
+
+impl HitRecorder {
+    pub fn new(sample_rate: f32) -> Self {
+        HitRecorder {
+            frame: 0,
+            attack: None,
+            release: None,
+
+            statistics: HitStatistics {
+                attack_lengths: [0; MAX_HITS],
+                sustain_lengths: [0; MAX_HITS],
+                count: 0,
+                sample_rate,
+            },
+            next: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.attack = None;
+        self.release = None;
+        self.statistics.count = 0;
+        self.next = 0;
+    }
+
+    pub fn statistics(&self) -> &HitStatistics {
+        &self.statistics
+    }
+
+    /// Takes the transitions of a block of `n_samples` samples. Returns
+    /// `true` if a hit has been completed.
+    pub fn record(&mut self, transitions: &Transitions, n_samples: usize) -> bool {
+        let mut completed = false;
+        for &(point, transition) in transitions.events() {
+            let frame = self.frame + point as u64;
+            match transition {
+                Transition::Attack => {
+                    // a new hit during the sustain ends the previous one
+                    completed |= self.complete_hit(frame);
+                    self.attack = Some(frame);
+                    self.release = None;
+                },
+                Transition::Release => {
+                    if self.attack.is_some() {
+                        self.release = Some(frame);
+                    }
+                },
+                Transition::Idle => {
+                    completed |= self.complete_hit(frame);
+                    self.attack = None;
+                    self.release = None;
+                }
+            }
+        }
+
+        self.frame += n_samples as u64;
+        completed
+    }
+
+    fn complete_hit(&mut self, end: u64) -> bool {
+        if let (Some(attack), Some(release)) = (self.attack, self.release) {
+            self.statistics.attack_lengths[self.next] = (release - attack) as u32;
+            self.statistics.sustain_lengths[self.next] = (end - release) as u32;
+            self.next = (self.next + 1) % MAX_HITS;
+            self.statistics.count = (self.statistics.count + 1).min(MAX_HITS);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Suggests smooth settings from the median lengths of the attack and the
+/// sustain part. The smooth times are time constants of the detectors, so
+/// they need to be well below the length of the part they are detecting.
+/// Runs in the worker thread.
+pub fn suggest(statistics: &HitStatistics) -> Option<Suggestion> {
+    if statistics.count < MIN_HITS {
+        return None;
+    }
+
+    let attack_length = median(&statistics.attack_lengths[..statistics.count]) / statistics.sample_rate;
+    let sustain_length = median(&statistics.sustain_lengths[..statistics.count]) / statistics.sample_rate;
+
+    Some(Suggestion {
+        attack_smooth: (attack_length * 0.5).max(0.0001).min(0.05),
+        sustain_smooth: (sustain_length * 0.25).max(0.001).min(0.2),
+    })
+}
+
+fn median(lengths: &[u32]) -> f32 {
+    let mut sorted = lengths.to_vec();
+    sorted.sort_unstable();
+    let mid = sorted.len() / 2;
+    if sorted.len() % 2 == 1 {
+        sorted[mid] as f32
+    } else {
+        (sorted[mid - 1] as f32 + sorted[mid] as f32) / 2.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn several_hits_in_one_block() {
+        let mut recorder = HitRecorder::new(1000.0);
+        let completed = recorder.record(&Transitions::from_events(&[
+            (10, Transition::Attack),
+            (20, Transition::Release),
+            (40, Transition::Idle),
+            (60, Transition::Attack),
+            (65, Transition::Release),
+            (90, Transition::Idle),
+        ]), 100);
+
+        assert!(completed);
+        let statistics = recorder.statistics();
+        assert_eq!(statistics.count, 2);
+        assert_eq!(statistics.attack_lengths[..2], [10, 5]);
+        assert_eq!(statistics.sustain_lengths[..2], [20, 25]);
+    }
+
+    #[test]
+    fn hit_over_several_blocks() {
+        let mut recorder = HitRecorder::new(1000.0);
+        assert!(!recorder.record(&Transitions::from_events(&[(90, Transition::Attack)]), 100));
+        assert!(!recorder.record(&Transitions::from_events(&[(5, Transition::Release)]), 100));
+        // a new attack during the sustain completes the hit
+        assert!(recorder.record(&Transitions::from_events(&[(50, Transition::Attack)]), 100));
+
+        let statistics = recorder.statistics();
+        assert_eq!(statistics.count, 1);
+        assert_eq!(statistics.attack_lengths[0], 15);
+        assert_eq!(statistics.sustain_lengths[0], 145);
+    }
+
+    #[test]
+    fn hit_without_release_is_not_recorded() {
+        let mut recorder = HitRecorder::new(1000.0);
+        assert!(!recorder.record(&Transitions::from_events(&[
+            (10, Transition::Attack),
+            (20, Transition::Idle),
+        ]), 100));
+        assert_eq!(recorder.statistics().count, 0);
+    }
+
+    /// The statistics of hits at 1 kHz with the lengths in frames
+    fn hit_statistics(attack_lengths: &[u32], sustain_lengths: &[u32]) -> HitStatistics {
+        let mut statistics = HitStatistics {
+            // garbage from hits that have been reset
+            attack_lengths: [1_000_000; MAX_HITS],
+            sustain_lengths: [1_000_000; MAX_HITS],
+            count: attack_lengths.len(),
+            sample_rate: 1000.0,
+        };
+        statistics.attack_lengths[..attack_lengths.len()].copy_from_slice(attack_lengths);
+        statistics.sustain_lengths[..sustain_lengths.len()].copy_from_slice(sustain_lengths);
+        statistics
+    }
+
+    #[test]
+    fn no_suggestion_from_few_hits() {
+        assert!(suggest(&hit_statistics(&[20; 3], &[100; 3])).is_none());
+    }
+
+    #[test]
+    fn suggestion_from_the_medians() {
+        let suggestion = suggest(&hit_statistics(&[10, 30, 20, 1000, 20], &[200, 100, 400, 80, 120])).unwrap();
+        assert!((suggestion.attack_smooth - 0.01).abs() < 1e-6);
+        assert!((suggestion.sustain_smooth - 0.03).abs() < 1e-6);
+
+        // the median of an even number of hits is between the middle ones
+        let suggestion = suggest(&hit_statistics(&[40, 10, 30, 20], &[100, 400, 200, 300])).unwrap();
+        assert!((suggestion.attack_smooth - 0.0125).abs() < 1e-6);
+        assert!((suggestion.sustain_smooth - 0.0625).abs() < 1e-6);
+    }
+
+    #[test]
+    fn suggestion_is_limited_to_the_parameter_ranges() {
+        let suggestion = suggest(&hit_statistics(&[0; 4], &[0; 4])).unwrap();
+        assert!((suggestion.attack_smooth - 0.0001).abs() < 1e-9);
+        assert!((suggestion.sustain_smooth - 0.001).abs() < 1e-9);
+
+        let suggestion = suggest(&hit_statistics(&[10_000; 4], &[10_000; 4])).unwrap();
+        assert!((suggestion.attack_smooth - 0.05).abs() < 1e-9);
+        assert!((suggestion.sustain_smooth - 0.2).abs() < 1e-9);
+    }
+}
@@ -1,46 +1,25 @@
-use std::f32::consts::PI;
+use std::any::Any;
+use std::ffi::c_void;
+use std::ptr::NonNull;
 
 use lv2::prelude::*;
+use lv2_atom::port::PortWriter;
+use lv2_atom::sequence::SequenceWriter;
 
-#[derive(PortCollection)]
-struct PortsMono {
-    enabled: InputPort<Control>,
-    use_sidechain: InputPort<Control>,
-    attack_boost: InputPort<Control>,
-    attack_smooth: InputPort<Control>,
-    sustain_boost: InputPort<Control>,
-    sustain_smooth: InputPort<Control>,
-    _gain_attack: InputPort<Control>,
-    _gain_release: InputPort<Control>,
-    outgain: InputPort<Control>,
-    mix: InputPort<Control>,
-    control: InputPort<AtomPort>,
-    notify: OutputPort<AtomPort>,
-    input: InputPort<Audio>,
-    sidechain_input: InputPort<Audio>,
-    output: OutputPort<Audio>
-}
+mod decimator;
+pub mod dsp;
+pub mod learn;
+mod onset;
+mod statistics;
+pub mod transport;
 
-#[derive(PortCollection)]
-struct PortsStereo {
-    enabled: InputPort<Control>,
-    use_sidechain: InputPort<Control>,
-    attack_boost: InputPort<Control>,
-    attack_smooth: InputPort<Control>,
-    sustain_boost: InputPort<Control>,
-    sustain_smooth: InputPort<Control>,
-    _gain_attack: InputPort<Control>,
-    _gain_release: InputPort<Control>,
-    outgain: InputPort<Control>,
-    mix: InputPort<Control>,
-    control: InputPort<AtomPort>,
-    notify: OutputPort<AtomPort>,
-    input_left: InputPort<Audio>,
-    input_right: InputPort<Audio>,
-    sidechain_input: InputPort<Audio>,
-    output_left: OutputPort<Audio>,
-    output_right: OutputPort<Audio>,
-}
+use decimator::*;
+use dsp::*;
+use learn::*;
+use statistics::*;
+use transport::*;
+
+use urids::protocol::*;
 
 #[derive(FeatureCollection)]
 struct Features<'a> {
@@ -48,125 +27,6 @@ struct Features<'a> {
     options: LV2Options
 }
 
-struct Dezipper {
-    target: f32,
-    current_value: f32,
-    coeff: f32,
-}
-
-impl Dezipper {
-    fn new(start_value: f32, sample_rate: f32) -> Self {
-        Dezipper {
-            target: start_value,
-            current_value: start_value,
-            coeff: 1.0 - (-2.0 * PI * 25. / sample_rate).exp()
-        }
-    }
-
-    fn set_value(&mut self, v: f32) {
-        self.target = v
-    }
-
-    fn process(&mut self) -> f32 {
-        self.current_value += self.coeff * (self.target - self.current_value);
-        self.current_value
-    }
-}
-
-
-struct EnvelopeDetector {
-    attack: f32,
-    release: f32,
-    sample_rate: f32,
-
-    current_level: f32,
-    y1: f32
-}
-
-impl EnvelopeDetector {
-    fn new(sample_rate: f32) -> Self {
-        EnvelopeDetector {
-            attack: 0.0,
-            release: 0.0,
-            sample_rate,
-            current_level: 0.0,
-            y1: 0.0
-        }
-    }
-
-    fn process(&mut self, level: f32) -> f32 {
-        if level >= self.current_level {
-            self.current_level = self.attack * (self.current_level - level) + level;
-        } else {
-            self.current_level = self.release * (self.current_level - level) + level;
-        }
-        self.current_level
-    }
-
-    fn level(&self) -> f32 {
-        self.current_level
-    }
-
-    fn set_params(&mut self, attack_time: f32, release_time: f32) {
-        self.attack = (-1.0 / (self.sample_rate * attack_time)).exp();
-//        self.y1 = self.attack;
-        self.release = (-1.0 / (self.sample_rate * release_time)).exp();
-    }
-
-    fn reset(&mut self, level: f32) {
-        self.y1 = self.attack;
-        self.current_level = level;
-    }
-}
-
-
-struct BeatDetector {
-    release: f32,
-
-    current_level: f32,
-    max_level: f32
-}
-
-impl BeatDetector {
-    fn new(sample_rate: f32, release_time: f32) -> Self {
-        BeatDetector {
-            release: (-1.0 / (sample_rate * release_time)).exp(),
-
-            current_level: 0.0,
-            max_level: 0.0
-        }
-    }
-
-    fn process(&mut self, level: f32) -> f32 {
-        if level >= self.current_level {
-            self.current_level = level;
-            self.max_level = self.current_level
-        } else {
-            self.current_level = self.release * (self.current_level - level) + level;
-        }
-        self.current_level
-    }
-
-    fn max_level(&self) -> f32 {
-        self.max_level
-    }
-
-    fn level(&self) -> f32 {
-        self.current_level
-    }
-}
-
-
-#[derive(PartialEq, Clone, Copy, Debug)]
-enum State {
-    Attack,
-    Release,
-    Idle,
-    Disabled
-}
-
-use State::*;
-
 struct EnvolvigoPorts<'a> {
     enabled: &'a InputPort<Control>,
     use_sidechain: &'a InputPort<Control>,
@@ -176,171 +36,401 @@ struct EnvolvigoPorts<'a> {
     sustain_smooth: &'a InputPort<Control>,
     outgain: &'a InputPort<Control>,
     mix: &'a InputPort<Control>,
+    sustain_sync: &'a InputPort<Control>,
+    sustain_note: &'a InputPort<Control>,
+    grid_enable: &'a InputPort<Control>,
+    grid_steps: &'a InputPort<Control>,
+    grid_mask: &'a InputPort<Control>,
+    freewheel: &'a InputPort<Control>,
+    trigger_source: &'a InputPort<Control>,
+    onset_detector: &'a InputPort<Control>,
+    adaptive_threshold: &'a InputPort<Control>,
+    threshold_margin: &'a InputPort<Control>,
+    attack_width: &'a InputPort<Control>,
+    sustain_width: &'a InputPort<Control>,
+    attack_tone: &'a InputPort<Control>,
+    sustain_tone: &'a InputPort<Control>,
+    attack_drive: &'a InputPort<Control>,
+    saturation: &'a InputPort<Control>,
+    attack_shape: &'a InputPort<Control>,
+    sustain_shape: &'a InputPort<Control>,
+    learn: &'a InputPort<Control>,
+    inspector: &'a InputPort<Control>,
+    channel_view: &'a InputPort<Control>,
     control: &'a InputPort<AtomPort>,
-    notify: &'a mut OutputPort<AtomPort>,
-    input: Vec<&'a InputPort<Audio>>,
-    sidechain_input: &'a InputPort<Audio>,
-    output: Vec<&'a mut OutputPort<Audio>>
-}
-
-#[uri("http://johannes-mueller.org/lv2/envolvigo#mono")]
-struct EnvolvigoMono {
-    engine: Envolvigo,
+    notify: &'a mut OutputPort<NotifyPort>,
+    midi_input: &'a InputPort<AtomPort>,
+    input: &'a [&'a [f32]],
+    sidechain_input: &'a [&'a [f32]],
+    output: &'a mut [&'a mut [f32]],
+    key_weights: &'a [f32]
 }
 
-#[uri("http://johannes-mueller.org/lv2/envolvigo#stereo")]
-struct EnvolvigoStereo {
-    engine: Envolvigo,
-}
-
-impl Plugin for EnvolvigoMono {
-    type Ports = PortsMono;
-
-    type InitFeatures = Features<'static>;
-    type AudioFeatures = ();
-
-    fn new(plugin_info: &PluginInfo, features: &mut Features<'static>) -> Option<Self> {
-        let sample_rate = plugin_info.sample_rate() as f32;
-        let urids: urids::URIDs = features.map.populate_collection()?;
-        let max_block_length = features
-            .options
-            .retrieve_option(urids.buf_size.max_block_length)
-            .and_then(|atom| atom.read(urids.atom.int, ()))
-            .unwrap_or(8192) as usize;
-
-        Some(Self {
-            engine: Envolvigo::new(urids, sample_rate, max_block_length)
-        })
-    }
+/// Defines the port collection and the plugin type of one channel layout
+///
+/// All the layouts share the same control ports and the MIDI input,
+/// followed by the audio ports. The sidechain input is linked like the main
+/// input. Layouts with more than two channels also have one key weight
+/// control port per channel after the audio ports. It determines how much
+/// the channel contributes to the detected level.
+///
+/// The sidechain channels follow the main inputs. The latency port, which
+/// only the host looks at, comes last.
+///
+/// The audio features are a distinct type per layout as they contain the
+/// worker schedule of the plugin type. Without a worker there is no learn
+/// mode.
+///
+/// The UI is shared by all the layouts, so the control ports come first and
+/// have the same indices everywhere. This breaks the port indices of the
+/// first mono and stereo versions, which is why they have new URIs.
+macro_rules! envolvigo_plugin {
+    ($plugin:ident, $ports:ident, $features:ident, $uri:literal,
+     inputs: [$($input:ident),+],
+     sidechains: [$sidechain:ident $(, $extra_sidechain:ident)*],
+     outputs: [$($output:ident),+],
+     key_weights: [$($key_weight:ident),*]) => {
+
+        #[derive(PortCollection)]
+        struct $ports {
+            enabled: InputPort<Control>,
+            use_sidechain: InputPort<Control>,
+            attack_boost: InputPort<Control>,
+            attack_smooth: InputPort<Control>,
+            sustain_boost: InputPort<Control>,
+            sustain_smooth: InputPort<Control>,
+            _gain_attack: InputPort<Control>,
+            _gain_release: InputPort<Control>,
+            outgain: InputPort<Control>,
+            mix: InputPort<Control>,
+            sustain_sync: InputPort<Control>,
+            sustain_note: InputPort<Control>,
+            grid_enable: InputPort<Control>,
+            grid_steps: InputPort<Control>,
+            grid_mask: InputPort<Control>,
+            freewheel: InputPort<Control>,
+            trigger_source: InputPort<Control>,
+            onset_detector: InputPort<Control>,
+            adaptive_threshold: InputPort<Control>,
+            threshold_margin: InputPort<Control>,
+            attack_width: InputPort<Control>,
+            sustain_width: InputPort<Control>,
+            attack_tone: InputPort<Control>,
+            sustain_tone: InputPort<Control>,
+            attack_drive: InputPort<Control>,
+            saturation: InputPort<Control>,
+            attack_shape: InputPort<Control>,
+            sustain_shape: InputPort<Control>,
+            learn: InputPort<Control>,
+            inspector: InputPort<Control>,
+            channel_view: InputPort<Control>,
+            control: InputPort<AtomPort>,
+            notify: OutputPort<NotifyPort>,
+            midi_input: InputPort<AtomPort>,
+            $($input: InputPort<Audio>,)+
+            $sidechain: InputPort<Audio>,
+            $($extra_sidechain: InputPort<Audio>,)*
+            $($output: OutputPort<Audio>,)+
+            $($key_weight: InputPort<Control>,)*
+            latency: OutputPort<Control>,
+        }
 
-    fn run(&mut self, ports: &mut PortsMono, _features: &mut (), _: u32) {
-        let mut engine_ports = EnvolvigoPorts {
-            enabled: &ports.enabled,
-            use_sidechain: &ports.use_sidechain,
-            attack_boost: &ports.attack_boost,
-            attack_smooth: &ports.attack_smooth,
-            sustain_boost: &ports.sustain_boost,
-            sustain_smooth: &ports.sustain_smooth,
-            outgain: &ports.outgain,
-            mix: &ports.mix,
-            control: &ports.control,
-            notify: &mut ports.notify,
-            input: vec![&ports.input],
-            sidechain_input: &ports.sidechain_input,
-            output: vec![&mut ports.output],
-        };
+        #[derive(FeatureCollection)]
+        struct $features<'a> {
+            schedule: Option<Schedule<'a, $plugin>>,
+        }
 
-        self.engine.run(&mut engine_ports);
-    }
-}
+        #[uri($uri)]
+        struct $plugin {
+            engine: Envolvigo,
+        }
 
-impl Plugin for EnvolvigoStereo {
-    type Ports = PortsStereo;
+        impl Plugin for $plugin {
+            type Ports = $ports;
+
+            type InitFeatures = Features<'static>;
+            type AudioFeatures = $features<'static>;
+
+            fn new(plugin_info: &PluginInfo, features: &mut Features<'static>) -> Option<Self> {
+                let sample_rate = plugin_info.sample_rate() as f32;
+                let urids: urids::URIDs = features.map.populate_collection()?;
+                let max_block_length = features
+                    .options
+                    .retrieve_option(urids.buf_size.max_block_length)
+                    .and_then(|atom| atom.read(urids.atom.int, ()))
+                    .unwrap_or(8192) as usize;
+                let sequence_size = features
+                    .options
+                    .retrieve_option(urids.buf_size.sequence_size)
+                    .and_then(|atom| atom.read(urids.atom.int, ()))
+                    .unwrap_or(DEFAULT_SEQUENCE_SIZE as i32) as usize;
+                let channels = [$(stringify!($input)),+].len();
+                let sidechain_channels =
+                    [stringify!($sidechain) $(, stringify!($extra_sidechain))*].len();
+
+                Some(Self {
+                    engine: Envolvigo::new(urids,
+                                           sample_rate,
+                                           max_block_length,
+                                           sequence_size,
+                                           channels,
+                                           sidechain_channels)
+                })
+            }
 
-    type InitFeatures = Features<'static>;
-    type AudioFeatures = ();
+            fn run(&mut self, ports: &mut $ports, features: &mut $features<'static>, _: u32) {
+                // the channels are collected on the stack
+                let input: &[&[f32]] = &[$(&*ports.$input),+];
+                let sidechain_input: &[&[f32]] = &[&*ports.$sidechain $(, &*ports.$extra_sidechain)*];
+                let output: &mut [&mut [f32]] = &mut [$(&mut *ports.$output),+];
+                let key_weights: &[f32] = &[$(**ports.$key_weight),*];
+                let mut engine_ports = EnvolvigoPorts {
+                    enabled: &ports.enabled,
+                    use_sidechain: &ports.use_sidechain,
+                    attack_boost: &ports.attack_boost,
+                    attack_smooth: &ports.attack_smooth,
+                    sustain_boost: &ports.sustain_boost,
+                    sustain_smooth: &ports.sustain_smooth,
+                    outgain: &ports.outgain,
+                    mix: &ports.mix,
+                    sustain_sync: &ports.sustain_sync,
+                    sustain_note: &ports.sustain_note,
+                    grid_enable: &ports.grid_enable,
+                    grid_steps: &ports.grid_steps,
+                    grid_mask: &ports.grid_mask,
+                    freewheel: &ports.freewheel,
+                    trigger_source: &ports.trigger_source,
+                    onset_detector: &ports.onset_detector,
+                    adaptive_threshold: &ports.adaptive_threshold,
+                    threshold_margin: &ports.threshold_margin,
+                    attack_width: &ports.attack_width,
+                    sustain_width: &ports.sustain_width,
+                    attack_tone: &ports.attack_tone,
+                    sustain_tone: &ports.sustain_tone,
+                    attack_drive: &ports.attack_drive,
+                    saturation: &ports.saturation,
+                    attack_shape: &ports.attack_shape,
+                    sustain_shape: &ports.sustain_shape,
+                    learn: &ports.learn,
+                    inspector: &ports.inspector,
+                    channel_view: &ports.channel_view,
+                    control: &ports.control,
+                    notify: &mut ports.notify,
+                    midi_input: &ports.midi_input,
+                    input,
+                    sidechain_input,
+                    output,
+                    key_weights,
+                };
+
+                let schedule = features.schedule.as_mut();
+                if let Some(statistics) = self.engine.run(&mut engine_ports, schedule.is_some()) {
+                    // If the worker is busy, we just try again after the
+                    // next hit.
+                    if let Some(schedule) = schedule {
+                        let _ = schedule.schedule_work(statistics);
+                    }
+                }
+                **ports.latency = self.engine.latency() as f32;
+            }
 
-    fn new(plugin_info: &PluginInfo, features: &mut Features<'static>) -> Option<Self> {
-        let sample_rate = plugin_info.sample_rate() as f32;
-        let urids: urids::URIDs = features.map.populate_collection()?;
-        let max_block_length = features
-            .options
-            .retrieve_option(urids.buf_size.max_block_length)
-            .and_then(|atom| atom.read(urids.atom.int, ()))
-            .unwrap_or(8192) as usize;
+            fn extension_data(uri: &Uri) -> Option<&'static dyn Any> {
+                match_extensions![uri, WorkerDescriptor<Self>]
+            }
+        }
 
-        Some(Self {
-            engine: Envolvigo::new(urids, sample_rate, max_block_length)
-        })
-    }
+        impl Worker for $plugin {
+            type WorkData = HitStatistics;
+            type ResponseData = Suggestion;
 
-    fn run(&mut self, ports: &mut PortsStereo, _features: &mut (), _: u32) {
-        let mut engine_ports = EnvolvigoPorts {
-            enabled: &ports.enabled,
-            use_sidechain: &ports.use_sidechain,
-            attack_boost: &ports.attack_boost,
-            attack_smooth: &ports.attack_smooth,
-            sustain_boost: &ports.sustain_boost,
-            sustain_smooth: &ports.sustain_smooth,
-            outgain: &ports.outgain,
-            mix: &ports.mix,
-            control: &ports.control,
-            notify: &mut ports.notify,
-            input: vec![&ports.input_left, &ports.input_right],
-            sidechain_input: &ports.sidechain_input,
-            output: vec![&mut ports.output_left, &mut ports.output_right],
-        };
+            fn work(response_handler: &ResponseHandler<Self>,
+                    statistics: HitStatistics) -> Result<(), WorkerError> {
+                if let Some(suggestion) = suggest(&statistics) {
+                    response_handler.respond(suggestion).map_err(|_| WorkerError::NoSpace)?;
+                }
+                Ok(())
+            }
 
-        self.engine.run(&mut engine_ports);
+            fn work_response(&mut self,
+                             suggestion: Suggestion,
+                             _features: &mut $features<'static>) -> Result<(), WorkerError> {
+                self.engine.suggestion = Some(suggestion);
+                Ok(())
+            }
+        }
     }
 }
 
+envolvigo_plugin!(EnvolvigoMono, PortsMono, AudioFeaturesMono,
+                  "http://johannes-mueller.org/lv2/envolvigo#mono2",
+                  inputs: [input],
+                  sidechains: [sidechain_input],
+                  outputs: [output],
+                  key_weights: []);
+
+envolvigo_plugin!(EnvolvigoStereo, PortsStereo, AudioFeaturesStereo,
+                  "http://johannes-mueller.org/lv2/envolvigo#stereo2",
+                  inputs: [input_left, input_right],
+                  sidechains: [sidechain_left, sidechain_right],
+                  outputs: [output_left, output_right],
+                  key_weights: []);
+
+envolvigo_plugin!(EnvolvigoQuad, PortsQuad, AudioFeaturesQuad,
+                  "http://johannes-mueller.org/lv2/envolvigo#quad",
+                  inputs: [input_fl, input_fr, input_rl, input_rr],
+                  sidechains: [sidechain_input],
+                  outputs: [output_fl, output_fr, output_rl, output_rr],
+                  key_weights: [key_weight_fl, key_weight_fr, key_weight_rl, key_weight_rr]);
+
+envolvigo_plugin!(EnvolvigoSurround51, PortsSurround51, AudioFeaturesSurround51,
+                  "http://johannes-mueller.org/lv2/envolvigo#surround51",
+                  inputs: [input_l, input_r, input_c, input_lfe, input_ls, input_rs],
+                  sidechains: [sidechain_input],
+                  outputs: [output_l, output_r, output_c, output_lfe, output_ls, output_rs],
+                  key_weights: [key_weight_l, key_weight_r, key_weight_c, key_weight_lfe,
+                                key_weight_ls, key_weight_rs]);
+
+envolvigo_plugin!(EnvolvigoSurround71, PortsSurround71, AudioFeaturesSurround71,
+                  "http://johannes-mueller.org/lv2/envolvigo#surround71",
+                  inputs: [input_l, input_r, input_c, input_lfe, input_ls, input_rs, input_lrs, input_rrs],
+                  sidechains: [sidechain_input],
+                  outputs: [output_l, output_r, output_c, output_lfe, output_ls, output_rs,
+                            output_lrs, output_rrs],
+                  key_weights: [key_weight_l, key_weight_r, key_weight_c, key_weight_lfe,
+                                key_weight_ls, key_weight_rs, key_weight_lrs, key_weight_rrs]);
+
+envolvigo_plugin!(EnvolvigoAmbisonics1, PortsAmbisonics1, AudioFeaturesAmbisonics1,
+                  "http://johannes-mueller.org/lv2/envolvigo#ambisonics1",
+                  inputs: [input_w, input_y, input_z, input_x],
+                  sidechains: [sidechain_input],
+                  outputs: [output_w, output_y, output_z, output_x],
+                  key_weights: [key_weight_w, key_weight_y, key_weight_z, key_weight_x]);
+
+/// We don't allocate in `run()`, so note ons beyond this number in one
+/// block are dropped.
+const MAX_MIDI_TRIGGERS: usize = 128;
+
+/// The size of the notify buffer we assume if the host does not tell us.
+/// It is only used to size the buffers. What is written into the notify
+/// port is checked against the space the host actually provides.
+const DEFAULT_SEQUENCE_SIZE: usize = 8192;
+
+/// The header of any atom
+const ATOM_HEADER_SIZE: usize = 8;
+
+/// The header of the notify sequence
+const SEQUENCE_HEADER_SIZE: usize = 16;
+
+/// The plugin config is repeated that often in seconds while the UI is on.
+const CONFIG_INTERVAL: f32 = 1.0;
+
+/// The statistics of the hits are sent that often in seconds while the UI is
+/// on.
+const STATISTICS_INTERVAL: f32 = 1.0;
+
+/// The number of transitions kept between two notifications. If there are
+/// more, the UI is notified earlier than its update rate asks for. More
+/// transitions in one block are dropped.
+const MAX_PENDING_STATES: usize = 16;
+
 struct Envolvigo {
     urids: urids::URIDs,
     ui_active: bool,
-    ui_notified: bool,
+    /// A UI asked for the config, which is answered even if the UI is not on
+    config_requested: bool,
+    /// The frame at which the plugin config is to be sent next
+    next_config_frame: u64,
+    /// The frame at which the statistics are to be sent next
+    next_statistics_frame: u64,
+    /// The frames between two notifications of the UI, zero for every block
+    notify_interval: u64,
+    /// The frame at which the UI is to be notified next
+    next_notify_frame: u64,
+    /// The transitions since the last notification as absolute frames
+    pending_states: Vec<State>,
 
     sample_rate: f32,
 
-    beat_detector: BeatDetector,
-
-    attack_smooth: EnvelopeDetector,
-    sustain_smooth: EnvelopeDetector,
+    transport: Transport,
+    shaper: Shaper,
 
-    attack_slow: EnvelopeDetector,
-    attack_fast: EnvelopeDetector,
+    midi_triggers: Vec<Trigger>,
 
-    release_slow: EnvelopeDetector,
-    release_fast: EnvelopeDetector,
+    hit_recorder: HitRecorder,
+    suggestion: Option<Suggestion>,
+    /// The capabilities announced to the UI, depending on the host's features
+    capabilities: u32,
 
-    attack_boost: Dezipper,
-    sustain_boost: Dezipper,
+    statistics_recorder: StatisticsRecorder,
 
-    result_gain: EnvelopeDetector,
-
-    outgain: Dezipper,
-    mix: Dezipper,
-
-    gain_buffer: Vec<f32>,
+    frame: u64,
+    decimator: Decimator,
+    dropped_messages: u32,
     input_buffer: Vec<f32>,
-    output_buffer: Vec<f32>,
-
-    state: State,
+    input_channels_buffer: Vec<[f32; 2]>,
 }
 
 impl Envolvigo {
-    fn new(urids: urids::URIDs, sample_rate: f32, max_block_length: usize) -> Self {
+    fn new(urids: urids::URIDs,
+           sample_rate: f32,
+           max_block_length: usize,
+           sequence_size: usize,
+           channels: usize,
+           sidechain_channels: usize) -> Self {
+        let notify_capacity = sequence_size.saturating_sub(SEQUENCE_HEADER_SIZE);
+        // the other messages of a block must fit in besides the audio data
+        let max_state = State {
+            attack_point: Some(0),
+            release_point: None,
+            idle_point: None,
+        };
+        let reserve = [
+            PluginMessage::Overflow { dropped_messages: 0 },
+            PluginMessage::Config {
+                sample_rate,
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: CAPABILITIES,
+            },
+            PluginMessage::LearnResult { attack_smooth: 0.0, sustain_smooth: 0.0 },
+            PluginMessage::Statistics(Statistics::default()),
+        ].iter().map(PluginMessage::size).sum::<usize>()
+            + MAX_PENDING_STATES * PluginMessage::State(max_state).size();
+        let stereo = channels == 2;
+        let max_buckets = AudioData::max_buckets(notify_capacity.saturating_sub(reserve), stereo, false);
+        let max_inspector_buckets = AudioData::max_buckets(notify_capacity.saturating_sub(reserve), stereo, true);
+
         Envolvigo {
                 ui_active: false,
-                ui_notified: false,
+                config_requested: false,
+                next_config_frame: 0,
+                next_statistics_frame: 0,
+                notify_interval: 0,
+                next_notify_frame: 0,
+                pending_states: Vec::with_capacity(MAX_PENDING_STATES),
                 urids,
 
                 sample_rate,
 
-                beat_detector: BeatDetector::new(sample_rate, 0.2),
-
-                attack_smooth: EnvelopeDetector::new(sample_rate),
-                sustain_smooth: EnvelopeDetector::new(sample_rate),
-
-                attack_slow: EnvelopeDetector::new(sample_rate),
-                attack_fast: EnvelopeDetector::new(sample_rate),
-
-                release_slow: EnvelopeDetector::new(sample_rate),
-                release_fast: EnvelopeDetector::new(sample_rate),
+                transport: Transport::new(sample_rate),
+                shaper: Shaper::new(sample_rate as f64, max_block_length, channels, sidechain_channels),
 
-                attack_boost: Dezipper::new(0.0, sample_rate),
-                sustain_boost: Dezipper::new(0.0, sample_rate),
+                midi_triggers: Vec::with_capacity(MAX_MIDI_TRIGGERS),
 
-                result_gain: EnvelopeDetector::new(sample_rate),
+                hit_recorder: HitRecorder::new(sample_rate),
+                suggestion: None,
+                capabilities: CAPABILITIES,
 
-                outgain: Dezipper::new(1.0, sample_rate),
-                mix: Dezipper::new(1.0, sample_rate),
+                statistics_recorder: StatisticsRecorder::new(sample_rate),
 
-                gain_buffer: Vec::with_capacity(max_block_length),
+                frame: 0,
+                decimator: Decimator::new(sample_rate,
+                                          max_block_length,
+                                          max_buckets,
+                                          max_inspector_buckets,
+                                          stereo),
+                dropped_messages: 0,
                 input_buffer: Vec::with_capacity(max_block_length),
-                output_buffer: Vec::with_capacity(max_block_length),
-
-                state: Idle,
+                input_channels_buffer: Vec::with_capacity(max_block_length),
         }
     }
 
@@ -352,241 +442,516 @@ impl Envolvigo {
                 Some(cs) => cs
             };
 
-        for (_, message) in control_sequence {
-            if let Some((header,  _)) = message.read(self.urids.atom.object, ()) {
-                if header.otype == self.urids.ui_on {
-                    self.ui_active = true;
-                    self.ui_notified = false;
-                } else if header.otype == self.urids.ui_off {
-                    self.ui_active = false;
+        for (timestamp, message) in control_sequence {
+            if let Some((header, object_reader)) = message.read(self.urids.atom.object, ()) {
+                if header.otype == self.urids.time.position_class {
+                    self.transport.update(object_reader,
+                                          &self.urids,
+                                          timestamp.as_frames().unwrap_or(0));
+                } else if let Some(ui_message) = UIMessage::read(&self.urids, &header, object_reader) {
+                    match ui_message {
+                        UIMessage::On => {
+                            self.ui_active = true;
+                            self.next_config_frame = self.frame;
+                            self.next_statistics_frame = self.frame;
+                            self.next_notify_frame = self.frame;
+                            self.pending_states.clear();
+                            self.decimator.reset();
+                        }
+                        UIMessage::Off => self.ui_active = false,
+                        UIMessage::RequestConfig => {
+                            self.config_requested = true;
+                            self.next_config_frame = self.frame;
+                        }
+                        UIMessage::Resolution { buckets_per_second } => {
+                            self.decimator.set_resolution(buckets_per_second);
+                        }
+                        UIMessage::ResetStatistics => {
+                            self.statistics_recorder.reset();
+                            self.next_statistics_frame = self.frame;
+                        }
+                        UIMessage::UpdateRate { updates_per_second } => {
+                            self.set_update_rate(updates_per_second);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The UI is notified at most `updates_per_second` times per second.
+    fn set_update_rate(&mut self, updates_per_second: f32) {
+        if !updates_per_second.is_finite() || updates_per_second <= 0.0 {
+            return;
+        }
+        self.notify_interval = (self.sample_rate / updates_per_second) as u64;
+        self.next_notify_frame = self.frame;
+    }
+
+    fn check_midi_events(&mut self, ports: &EnvolvigoPorts) {
+        self.midi_triggers.clear();
+
+        let midi_sequence = match ports
+            .midi_input
+            .read(self.urids.atom.sequence, self.urids.unit.beat) {
+                None => return,
+                Some(ms) => ms
+            };
+
+        for (timestamp, message) in midi_sequence {
+            let frame = match timestamp.as_frames() {
+                Some(frame) if frame >= 0 => frame as usize,
+                _ => continue
+            };
+            if let Some(&[status, _note, velocity]) = message.read(self.urids.midi.raw, ()) {
+                // a note on with velocity 0 is a note off
+                if status & 0xf0 == 0x90 && velocity > 0
+                    && self.midi_triggers.len() < MAX_MIDI_TRIGGERS {
+                    self.midi_triggers.push(Trigger {
+                        frame,
+                        velocity: velocity as f32 / 127.0
+                    });
                 }
             }
         }
     }
 
-    fn run(&mut self, ports: &mut EnvolvigoPorts) {
-        self.attack_fast.set_params(0.0, 0.02);
-        self.attack_slow.set_params(0.02, 5.0);
+    fn parameters(&self, ports: &EnvolvigoPorts) -> Parameters {
+        let sustain_length = if **ports.sustain_sync > 0.5 {
+            let note = NOTE_VALUES[(ports.sustain_note.max(0.0) as usize).min(NOTE_VALUES.len() - 1)];
+            self.transport
+                .note_length(note)
+                .map(|length| (length * self.sample_rate) as usize)
+        } else {
+            None
+        };
 
-        self.release_fast.set_params(0.01, 0.02);
-        self.release_slow.set_params(0.02, 0.025);
+        let beat_grid = if **ports.grid_enable > 0.5 {
+            Some(BeatGrid {
+                steps: ports.grid_steps.max(1.0).min(16.0) as u32,
+                mask: ports.grid_mask.max(0.0) as u32
+            })
+        } else {
+            None
+        };
+
+        let quality = if **ports.freewheel > 0.5 {
+            OFFLINE_QUALITY
+        } else {
+            REALTIME_QUALITY
+        };
 
-        self.attack_boost.set_value(ports.attack_boost.max(-30.0).min(30.0));
-        self.sustain_boost.set_value(ports.sustain_boost.max(-30.0).min(30.0));
+        let trigger_source = if **ports.trigger_source > 0.5 {
+            TriggerSource::Midi
+        } else {
+            TriggerSource::Audio
+        };
 
-        self.attack_smooth.set_params(0.0, ports.attack_smooth.max(0.0001).min(0.05));
-        let sustain_smooth = ports.sustain_smooth.max(0.001).min(0.2);
-        self.sustain_smooth.set_params(sustain_smooth, sustain_smooth);
+        let onset_detector = if **ports.onset_detector > 0.5 {
+            OnsetDetector::SpectralFlux
+        } else {
+            OnsetDetector::Level
+        };
 
-        let (mut state, mix) = if **ports.enabled > 0.5 {
-            (
-                match self.state {
-                    Disabled => Idle,
-                    state => state
-                },
-                ports.mix.max(0.0).min(1.0)
-            )
+        let adaptive_threshold = if **ports.adaptive_threshold > 0.5 {
+            Some(ports.threshold_margin.max(0.0).min(24.0) as f64)
         } else {
-            (
-                Disabled,
-                0.0
-            )
+            None
         };
 
-        let sidechain = **ports.use_sidechain > 0.5;
+        let saturation = match ports.saturation.max(0.0) as u32 {
+            0 => Saturation::Tape,
+            1 => Saturation::Tube,
+            _ => Saturation::Hard
+        };
 
-        self.outgain.set_value(from_dB(ports.outgain.max(-60.0).min(6.0)));
-        self.mix.set_value(mix);
+        let mut key_weights = [1.0; MAX_CHANNELS];
+        for (weight, port) in Iterator::zip(key_weights.iter_mut(), ports.key_weights.iter()) {
+            *weight = port.max(0.0).min(1.0);
+        }
 
-        self.check_notification_events(ports);
+        Parameters {
+            enabled: **ports.enabled > 0.5,
+            use_sidechain: **ports.use_sidechain > 0.5,
+            key_weights,
+            attack_boost: ports.attack_boost.max(-30.0).min(30.0) as f64,
+            attack_smooth: ports.attack_smooth.max(0.0001).min(0.05) as f64,
+            sustain_boost: ports.sustain_boost.max(-30.0).min(30.0) as f64,
+            sustain_smooth: ports.sustain_smooth.max(0.001).min(0.2) as f64,
+            attack_width: ports.attack_width.max(-30.0).min(30.0) as f64,
+            sustain_width: ports.sustain_width.max(-30.0).min(30.0) as f64,
+            attack_tone: ports.attack_tone.max(-1.0).min(1.0) as f64,
+            sustain_tone: ports.sustain_tone.max(-1.0).min(1.0) as f64,
+            attack_drive: ports.attack_drive.max(0.0).min(24.0) as f64,
+            saturation,
+            attack_shape: ports.attack_shape.max(-1.0).min(1.0) as f64,
+            sustain_shape: ports.sustain_shape.max(-1.0).min(1.0) as f64,
+            outgain: ports.outgain.max(-60.0).min(6.0) as f64,
+            mix: ports.mix.max(0.0).min(1.0) as f64,
+            sustain_length,
+            beat_grid,
+            trigger_source,
+            onset_detector,
+            adaptive_threshold,
+            quality,
+            // nobody would look at the envelopes
+            inspector: **ports.inspector > 0.5 && self.ui_active
+        }
+    }
+
+    /// Writes the messages collected since the last notification to the UI
+    /// as far as they fit into the notify port. The messages that don't fit
+    /// are reported to the UI as soon as there is space again.
+    fn notify_ui(&mut self,
+                 sequence_writer: &mut SequenceWriter,
+                 space: usize,
+                 params: &Parameters) {
+        let mut notifier = Notifier {
+            urids: &self.urids,
+            sequence_writer,
+            space_left: space,
+            dropped_messages: 0,
+        };
+
+        if self.dropped_messages > 0
+            && notifier.write(&PluginMessage::Overflow { dropped_messages: self.dropped_messages }) {
+            self.dropped_messages = 0;
+        }
+
+        if self.frame >= self.next_config_frame && notifier.write(&self.config()) {
+            self.next_config_frame = self.frame + (CONFIG_INTERVAL * self.sample_rate) as u64;
+            self.config_requested = false;
+        }
 
-        self.gain_buffer.clear();
+        if let Some(suggestion) = self.suggestion {
+            let message = PluginMessage::LearnResult {
+                attack_smooth: suggestion.attack_smooth,
+                sustain_smooth: suggestion.sustain_smooth,
+            };
+            if notifier.write(&message) {
+                self.suggestion = None;
+            }
+        }
+
+        if self.frame >= self.next_statistics_frame
+            && notifier.write(&PluginMessage::Statistics(self.statistics_recorder.statistics())) {
+            self.next_statistics_frame = self.frame + (STATISTICS_INTERVAL * self.sample_rate) as u64;
+        }
+
+        // the transitions go first, so that the UI knows where a hit starts
+        // when the audio data arrives
+        for &state in self.pending_states.iter() {
+            notifier.write(&PluginMessage::State(state));
+        }
+
+        let audio_data = self.decimator.audio_data(params.use_sidechain,
+                                                   params.adaptive_threshold.is_some());
+        if let Some(audio_data) = audio_data {
+            // rather send the first buckets than nothing
+            let n_buckets = AudioData::max_buckets(notifier.space_left,
+                                                   audio_data.stereo.is_some(),
+                                                   audio_data.inspector.is_some())
+                .min(audio_data.len());
+            if n_buckets < audio_data.len() {
+                notifier.dropped_messages += 1;
+            }
+            if n_buckets > 0 {
+                notifier.write(&PluginMessage::AudioData(audio_data.truncated(n_buckets)));
+            }
+        }
+
+        self.dropped_messages += notifier.dropped_messages;
+    }
+
+    /// Answers a config request of a UI that has not switched the
+    /// notifications on. Nothing else is sent to it.
+    fn answer_config_request(&mut self, sequence_writer: &mut SequenceWriter, space: usize) {
+        let config = self.config();
+        let mut notifier = Notifier {
+            urids: &self.urids,
+            sequence_writer,
+            space_left: space,
+            dropped_messages: 0,
+        };
+        if notifier.write(&config) {
+            self.config_requested = false;
+        }
+    }
+
+    fn config(&self) -> PluginMessage<'static> {
+        PluginMessage::Config {
+            sample_rate: self.sample_rate,
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: self.capabilities,
+        }
+    }
+
+    /// The lookahead of the shaper in frames, which depends on the quality
+    /// of the last block
+    fn latency(&self) -> usize {
+        self.shaper.latency()
+    }
+
+    /// Returns the statistics of the last hits if the worker should make a
+    /// new suggestion from them. Without a worker the learn mode is off.
+    fn run(&mut self, ports: &mut EnvolvigoPorts, worker_available: bool) -> Option<HitStatistics> {
+        self.capabilities = if worker_available {
+            CAPABILITIES
+        } else {
+            CAPABILITIES & !CAPABILITY_LEARN
+        };
+        self.check_notification_events(ports);
+        self.check_midi_events(ports);
 
-        let mut attack_point: Option<usize> = None;
-        let mut release_point: Option<usize> = None;
-        let mut idle_point: Option<usize> = None;
+        let params = self.parameters(ports);
 
         let n_samples = ports.input[0].len();
 
+        let channel_view = match ports.channel_view.max(0.0) as u32 {
+            0 => ChannelView::Max,
+            1 => ChannelView::Left,
+            2 => ChannelView::Right,
+            3 => ChannelView::Mid,
+            _ => ChannelView::Side
+        };
+
+        // the input has to be looked at before the processing as the host
+        // may use the same buffer for the output
         if self.ui_active {
             self.input_buffer.clear();
+            self.input_channels_buffer.clear();
             for i in 0..n_samples {
-                let v = ports.input.iter().fold(-160.0f32, |acc, v| acc.max(to_dB(&v[i])));
-                self.input_buffer.push(v);
+                self.input_buffer.push(channel_view.level(ports.input, i));
+                if let [left, right] = ports.input[..] {
+                    self.input_channels_buffer.push([left[i].abs(), right[i].abs()]);
+                }
             }
         }
 
-        for i in 0..n_samples {
-            let attack_boost = self.attack_boost.process();
-            let sustain_boost = self.sustain_boost.process();
-
-            let lvl = if sidechain {
-                ports.sidechain_input[i].abs()
+        let transitions = self.shaper.process(&params,
+                                              &self.transport,
+                                              &self.midi_triggers,
+                                              ports.input,
+                                              ports.sidechain_input,
+                                              ports.output);
+        self.transport.advance(n_samples);
+        self.statistics_recorder.record(&transitions, self.shaper.gain_signal());
+
+        let statistics = if worker_available && **ports.learn > 0.5 {
+            if self.hit_recorder.record(&transitions, n_samples)
+                && self.hit_recorder.statistics().count >= MIN_HITS {
+                Some(*self.hit_recorder.statistics())
             } else {
-                ports.input.iter().fold(0.0f32, |acc, v| acc.max(v[i].abs()))
-            };
+                None
+            }
+        } else {
+            self.hit_recorder.reset();
+            None
+        };
 
-            let old_lvl = self.beat_detector.level();
-            //println!("{} {} {}", lvl, old_lvl, in_frame);
-            let beat_detect = self.beat_detector.process(lvl);
-
-            if beat_detect > old_lvl && state != Disabled {
-                if state != Attack {
-                    self.attack_fast.reset(0.0);
-                    self.attack_slow.reset(0.0);
-                    self.attack_smooth.reset(self.result_gain.level());
-                    //println!("ATK {} {}", lvl, old_lvl);
-                    if attack_point.is_none() {
-                        attack_point = Some(i);
-                    }
-                }
-                state = Attack;
+        if self.ui_active {
+            self.decimator.set_inspector(params.inspector);
+            self.decimator.start_block(self.frame);
+            let gain_signal = self.shaper.gain_signal();
+            let threshold_signal = self.shaper.threshold_signal();
+            let envelopes = self.shaper.envelopes();
+            for (i, (&gain, &input)) in gain_signal.iter().zip(self.input_buffer.iter()).enumerate() {
+                self.decimator.push(Frame {
+                    gain,
+                    input,
+                    sidechain: ports.sidechain_input.iter().fold(0.0f32, |acc, v| acc.max(v[i].abs())),
+                    threshold: threshold_signal.get(i).copied().unwrap_or(-160.0),
+                    output: channel_view.level(ports.output, i),
+                    input_channels: self.input_channels_buffer.get(i).copied().unwrap_or_default(),
+                    output_channels: match &ports.output[..] {
+                        [left, right] => [left[i].abs(), right[i].abs()],
+                        _ => [0.0; 2]
+                    },
+                    envelopes: envelopes.get(i).copied().unwrap_or_default(),
+                });
             }
 
-            let gain = match state {
-                Attack => {
-                    let atk_fast = self.attack_fast.process(lvl);
-                    let atk_slow = self.attack_slow.process(lvl);
-                    let delta_atk = atk_fast - atk_slow;
-
-                    let pregain = self.attack_smooth.process(
-                        from_dB(delta_atk / self.beat_detector.max_level())
-                    );
-
-                    let gain = pregain.powf(attack_boost);
-                    if pregain < 1.0 {
-                        //println!("REL {} {} {} {}", lvl, delta_atk, atk_fast, atk_slow);
-                        state = Release;
-                        release_point = Some(i);
-                        self.release_fast.reset(atk_slow);
-                        self.release_slow.reset(0.0);
-                        self.sustain_smooth.reset(pregain);
-                    }
-                    gain
+            // one state message per transition, so that the UI sees them
+            // in order
+            for &(point, transition) in transitions.events() {
+                let point = Some(self.frame + point as u64);
+                let state = match transition {
+                    Transition::Attack => State { attack_point: point, release_point: None, idle_point: None },
+                    Transition::Release => State { attack_point: None, release_point: point, idle_point: None },
+                    Transition::Idle => State { attack_point: None, release_point: None, idle_point: point },
+                };
+                if self.pending_states.len() < MAX_PENDING_STATES {
+                    self.pending_states.push(state);
+                } else {
+                    self.dropped_messages += 1;
                 }
-                Release => {
-                    let rel_fast = self.release_fast.process(lvl);
-                    let rel_slow = self.release_slow.process(lvl);
-
-                    let delta_rel = rel_fast - rel_slow;
-                    let pregain = self.sustain_smooth.process(
-                        from_dB(
-                            delta_rel / self.attack_slow.level()
-                                * (15.0+3.0*ports.sustain_smooth.log10()) / 7.0
-                            // voodoo to compensate smoothening
-                        )
-                    );
-
-                    if to_dB(&pregain) < 0.0 {
-                        //println!("IDLE {} {} {} {}", lvl, delta_rel, rel_fast, rel_slow);
-                        idle_point = Some(i);
-                        state = Idle;
-                    }
-                    pregain.powf(sustain_boost)
-                }
-                Idle | Disabled => {
-                    self.sustain_smooth.process(1.0)
-                }
-            };
-            let gain = self.result_gain.process(gain);
-
-            self.gain_buffer.push(gain);
-
-            let mix = self.mix.process();
-            let gain = gain * self.outgain.process();
-            for (input, output) in Iterator::zip(ports.input.iter(), ports.output.iter_mut()) {
-                let out = input[i] * gain;
-                output[i] = out * mix + input[i] * (1.0 - mix);
             }
-        }
 
-        self.state = state;
+            // Between the notifications the data is collected, unless it
+            // would not fit into one notification anymore.
+            let notify = self.frame >= self.next_notify_frame
+                || self.decimator.is_full()
+                || self.pending_states.len() == MAX_PENDING_STATES;
 
-        if self.ui_active {
-            let mut sequence_writer = ports.notify.init(
+            let space = ports.notify.capacity.saturating_sub(SEQUENCE_HEADER_SIZE);
+            let sequence_writer = ports.notify.writer.init(
                 self.urids.atom.sequence,
                 TimeStampURID::Frames(self.urids.unit.frame)
-            ).unwrap();
-
-            if !self.ui_notified {
-                let mut object_writer = sequence_writer.init(
-                    TimeStamp::Frames(0),
-                    self.urids.atom.object,
-                    ObjectHeader {
-                        id: None,
-                        otype: self.urids.plugin_config.into_general(),
-                    }
-                ).unwrap();
-
-                object_writer.init(self.urids.parameters.sample_rate,
-                                   self.urids.atom.float,
-                                   self.sample_rate as f32);
-            }
-            self.ui_notified = true;
-
-            let mut object_writer = sequence_writer.init(
-                TimeStamp::Frames(0),
-                self.urids.atom.object,
-                ObjectHeader {
-                    id: None,
-                    otype: self.urids.plugin_state.into_general(),
+            );
+            if notify {
+                match sequence_writer {
+                    Some(mut sequence_writer) => self.notify_ui(&mut sequence_writer, space, &params),
+                    None => self.dropped_messages += 1,
                 }
-            ).unwrap();
-
-            if let Some(point) = attack_point {
-                object_writer.init(self.urids.attack_point, self.urids.atom.int, point as i32);
-            }
-            if let Some(point) = release_point {
-                object_writer.init(self.urids.release_point, self.urids.atom.int, point as i32);
+                self.decimator.clear();
+                self.pending_states.clear();
+                self.next_notify_frame = self.frame + self.notify_interval;
             }
-            if let Some(point) = idle_point {
-                object_writer.init(self.urids.idle_point, self.urids.atom.int, point as i32);
+        } else if self.config_requested {
+            let space = ports.notify.capacity.saturating_sub(SEQUENCE_HEADER_SIZE);
+            let sequence_writer = ports.notify.writer.init(
+                self.urids.atom.sequence,
+                TimeStampURID::Frames(self.urids.unit.frame)
+            );
+            if let Some(mut sequence_writer) = sequence_writer {
+                self.answer_config_request(&mut sequence_writer, space);
             }
+        }
 
-            let mut gain_writer: lv2_atom::vector::VectorWriter<Float> =
-                object_writer.init(self.urids.gain_signal,
-                                   self.urids.atom.vector(),
-                                   self.urids.atom.float).unwrap();
-            gain_writer.append(self.gain_buffer.iter().map(to_dB).collect::<Vec<f32>>().as_slice());
+        self.frame += n_samples as u64;
 
-            let mut input_writer: lv2_atom::vector::VectorWriter<Float> =
-                object_writer.init(self.urids.input_signal,
-                                   self.urids.atom.vector(),
-                                   self.urids.atom.float).unwrap();
-            input_writer.append(&self.input_buffer);
+        statistics
+    }
+}
 
-            let mut output_writer: lv2_atom::vector::VectorWriter<Float> =
-                object_writer.init(self.urids.output_signal,
-                                   self.urids.atom.vector(),
-                                   self.urids.atom.float).unwrap();
+/// The port type of the notify port. Unlike `AtomPort` it tells how much
+/// space the host provides, so that we never start writing a message that
+/// does not fit completely.
+struct NotifyPort;
 
-            self.output_buffer.clear();
-            for i in 0..n_samples {
-                let v = ports.output.iter().fold(-160.0f32, |acc, v| acc.max(to_dB(&v[i])));
-                self.output_buffer.push(v);
-            }
+struct NotifyWriter {
+    writer: PortWriter<'static>,
+    /// The size of the buffer in bytes including the atom header
+    capacity: usize,
+}
+
+impl PortType for NotifyPort {
+    type InputPortType = ();
+    type OutputPortType = NotifyWriter;
+
+    unsafe fn input_from_raw(_pointer: NonNull<c_void>, _sample_count: u32) {}
 
-            output_writer.append(&self.output_buffer);
+    unsafe fn output_from_raw(pointer: NonNull<c_void>, sample_count: u32) -> NotifyWriter {
+        // The host sets the size of the atom, its first field, to the size
+        // of the buffer without the atom header.
+        let size = *pointer.cast::<u32>().as_ref() as usize;
+        NotifyWriter {
+            writer: AtomPort::output_from_raw(pointer, sample_count),
+            capacity: ATOM_HEADER_SIZE + size,
         }
     }
 }
 
-#[allow(non_snake_case)]
-fn from_dB(v: f32) -> f32 {
-    10.0f32.powf(0.05 * v)
+/// Writes messages into the notify sequence as long as there is space left.
+/// A message is either written completely or not at all.
+struct Notifier<'a, 'b, 'c> {
+    urids: &'a urids::URIDs,
+    sequence_writer: &'a mut SequenceWriter<'b, 'c>,
+    space_left: usize,
+    dropped_messages: u32,
 }
 
-#[allow(non_snake_case)]
-fn to_dB(v: &f32) -> f32 {
-    20.0f32 * f32::log10(v.abs().max(1e-8))
+impl<'a, 'b, 'c> Notifier<'a, 'b, 'c> {
+    /// Returns `false` if the message has been dropped.
+    fn write(&mut self, message: &PluginMessage) -> bool {
+        let size = message.size();
+        if size <= self.space_left && message.write(self.urids, self.sequence_writer).is_some() {
+            self.space_left -= size;
+            true
+        } else {
+            self.dropped_messages += 1;
+            false
+        }
+    }
 }
 
-fn no_denormal(v: f32) -> f32 {
-    if v.is_normal() {
-        v
-    } else {
-        0.0
+lv2_descriptors!(EnvolvigoMono,
+                 EnvolvigoStereo,
+                 EnvolvigoQuad,
+                 EnvolvigoSurround51,
+                 EnvolvigoSurround71,
+                 EnvolvigoAmbisonics1);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lv2_atom::space::RootMutSpace;
+    use urid::mapper::HashURIDMapper;
+
+    const STATE: PluginMessage<'static> = PluginMessage::State(State {
+        attack_point: Some(0),
+        release_point: None,
+        idle_point: None,
+    });
+
+    const CONFIG: PluginMessage<'static> = PluginMessage::Config {
+        sample_rate: 48000.0,
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: CAPABILITIES,
+    };
+
+    /// Writes `messages` into a notify buffer of `size` bytes. Returns the
+    /// number of the messages that can be read back completely and the
+    /// number of dropped messages.
+    fn notify(size: usize, messages: &[PluginMessage]) -> (usize, u32) {
+        let map = HashURIDMapper::new();
+        let urids = urids::URIDs::from_map(&map).unwrap();
+        let mut raw_space: Box<[u8]> = vec![0; size].into_boxed_slice();
+
+        let dropped_messages = {
+            let mut space = RootMutSpace::new(raw_space.as_mut());
+            let mut sequence_writer = (&mut space as &mut dyn MutSpace)
+                .init(urids.atom.sequence, TimeStampURID::Frames(urids.unit.frame))
+                .unwrap();
+            let mut notifier = Notifier {
+                urids: &urids,
+                sequence_writer: &mut sequence_writer,
+                space_left: size - SEQUENCE_HEADER_SIZE,
+                dropped_messages: 0,
+            };
+            for message in messages {
+                notifier.write(message);
+            }
+            notifier.dropped_messages
+        };
+
+        let sequence = UnidentifiedAtom::new(Space::from_slice(raw_space.as_ref()))
+            .read(urids.atom.sequence, urids.unit.beat)
+            .unwrap();
+        let mut read_messages = 0;
+        for (_, atom) in sequence {
+            let (header, object_reader) = atom.read(urids.atom.object, ()).unwrap();
+            assert!(PluginMessage::read(&urids, &header, object_reader).is_some());
+            read_messages += 1;
+        }
+        (read_messages, dropped_messages)
     }
-}
 
-lv2_descriptors!(EnvolvigoMono, EnvolvigoStereo);
+    #[test]
+    fn messages_that_fit_exactly() {
+        let size = SEQUENCE_HEADER_SIZE + 2 * STATE.size();
+        assert_eq!(notify(size, &[STATE, STATE]), (2, 0));
+    }
+
+    #[test]
+    fn message_too_big_for_the_buffer_is_not_written() {
+        let size = SEQUENCE_HEADER_SIZE + STATE.size() - 8;
+        assert_eq!(notify(size, &[STATE]), (0, 1));
+    }
+
+    #[test]
+    fn smaller_message_fits_after_a_dropped_one() {
+        let size = SEQUENCE_HEADER_SIZE + 2 * STATE.size() + CONFIG.size() - 8;
+        assert_eq!(notify(size, &[STATE, CONFIG, STATE]), (2, 1));
+    }
+}
@@ -0,0 +1,245 @@
+use std::f32::consts::PI;
+
+// Onset detection by spectral flux
+//
+// The key signal is cut into overlapping frames of about 10ms. For each
+// frame the increase of the log magnitude spectrum over the previous frame
+// is averaged over the bins. An onset is reported when this flux exceeds the
+// recent average flux by a margin. Other than the broadband level, the flux
+// also rises when a new note starts in a dense mix or on tonal material.
+//
+// The flux is only calculated every hop, so an onset is reported up to one
+// hop after it happened, a bit more if it falls onto the faded edge of the
+// window. The shaper delays the level and the audio by this lag and reports
+// it as latency.
+
+/// Radix-2 complex FFT of a fixed power of two size
+struct Fft {
+    size: usize,
+    twiddles: Vec<(f32, f32)>,
+    bit_reverse: Vec<usize>,
+}
+
+impl Fft {
+    fn new(size: usize) -> Self {
+        let bits = size.trailing_zeros();
+        let usize_bits = (std::mem::size_of::<usize>() * 8) as u32;
+        Fft {
+            size,
+            twiddles: (0..size / 2).map(|k| {
+                let phi = -2.0 * PI * k as f32 / size as f32;
+                (phi.cos(), phi.sin())
+            }).collect(),
+            bit_reverse: (0..size).map(|i| i.reverse_bits() >> (usize_bits - bits)).collect(),
+        }
+    }
+
+    fn process(&self, re: &mut [f32], im: &mut [f32]) {
+        for (i, &j) in self.bit_reverse.iter().enumerate() {
+            if j > i {
+                re.swap(i, j);
+                im.swap(i, j);
+            }
+        }
+
+        let mut len = 2;
+        while len <= self.size {
+            let half = len / 2;
+            let stride = self.size / len;
+            for start in (0..self.size).step_by(len) {
+                for k in 0..half {
+                    let (wr, wi) = self.twiddles[k * stride];
+                    let a = start + k;
+                    let b = a + half;
+                    let tr = re[b] * wr - im[b] * wi;
+                    let ti = re[b] * wi + im[b] * wr;
+                    re[b] = re[a] - tr;
+                    im[b] = im[a] - ti;
+                    re[a] += tr;
+                    im[a] += ti;
+                }
+            }
+            len *= 2;
+        }
+    }
+}
+
+
+/// The flux has to exceed the average flux by this factor ...
+const THRESHOLD_FACTOR: f32 = 1.5;
+/// ... and this offset to be an onset.
+const THRESHOLD_OFFSET: f32 = 0.01;
+
+pub(crate) struct SpectralFlux {
+    fft: Fft,
+    window: Vec<f32>,
+    hop_size: usize,
+
+    history: Vec<f32>,
+    write_pos: usize,
+    hop_count: usize,
+
+    re: Vec<f32>,
+    im: Vec<f32>,
+    log_magnitudes: Vec<f32>,
+
+    flux_history: Vec<f32>,
+    flux_pos: usize,
+    flux_sum: f32,
+
+    above_threshold: bool,
+    holdoff: usize,
+    min_distance: usize,
+}
+
+impl SpectralFlux {
+    pub(crate) fn new(sample_rate: f64) -> Self {
+        let frame_size = ((sample_rate * 0.01) as usize).max(64).next_power_of_two();
+        let hop_size = frame_size / 4;
+        let average_hops = ((sample_rate * 0.15) as usize / hop_size).max(1);
+
+        // Hann window, scaled such that a full scale sine gives a magnitude
+        // of about 1.0
+        let window_sum = frame_size as f32 / 2.0;
+        let window = (0..frame_size)
+            .map(|i| (0.5 - 0.5 * (2.0 * PI * i as f32 / frame_size as f32).cos()) * 2.0 / window_sum)
+            .collect();
+
+        SpectralFlux {
+            fft: Fft::new(frame_size),
+            window,
+            hop_size,
+
+            history: vec![0.0; frame_size],
+            write_pos: 0,
+            hop_count: 0,
+
+            re: vec![0.0; frame_size],
+            im: vec![0.0; frame_size],
+            log_magnitudes: vec![0.0; frame_size / 2 + 1],
+
+            flux_history: vec![0.0; average_hops],
+            flux_pos: 0,
+            flux_sum: 0.0,
+
+            above_threshold: false,
+            holdoff: 0,
+            min_distance: (sample_rate * 0.08) as usize,
+        }
+    }
+
+    /// The frames an onset is reported late at most
+    pub(crate) fn latency(&self) -> usize {
+        self.hop_size + self.hop_size / 4
+    }
+
+    /// Feeds one sample of the key signal. Returns `true` if an onset has
+    /// been detected.
+    pub(crate) fn process(&mut self, v: f32) -> bool {
+        let frame_size = self.history.len();
+        self.history[self.write_pos] = v;
+        self.write_pos = (self.write_pos + 1) % frame_size;
+        self.holdoff = self.holdoff.saturating_sub(1);
+
+        self.hop_count += 1;
+        if self.hop_count < self.hop_size {
+            return false;
+        }
+        self.hop_count = 0;
+
+        let flux = self.flux();
+        let average = self.flux_sum / self.flux_history.len() as f32;
+        let threshold = average * THRESHOLD_FACTOR + THRESHOLD_OFFSET;
+
+        self.flux_sum += flux - self.flux_history[self.flux_pos];
+        self.flux_history[self.flux_pos] = flux;
+        self.flux_pos = (self.flux_pos + 1) % self.flux_history.len();
+
+        // only the crossing of the threshold is an onset, not every hop
+        // that stays above it
+        let was_above = self.above_threshold;
+        self.above_threshold = flux > threshold;
+        if self.above_threshold && !was_above && self.holdoff == 0 {
+            self.holdoff = self.min_distance;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn flux(&mut self) -> f32 {
+        let (newer, older) = self.history.split_at(self.write_pos);
+        let frame = Iterator::zip(older.iter().chain(newer.iter()), self.window.iter());
+        for ((re, im), (&v, &w)) in Iterator::zip(self.re.iter_mut().zip(self.im.iter_mut()), frame) {
+            *re = v * w;
+            *im = 0.0;
+        }
+        self.fft.process(&mut self.re, &mut self.im);
+
+        let mut flux = 0.0;
+        let bins = Iterator::zip(self.re.iter(), self.im.iter());
+        for (last, (&re, &im)) in Iterator::zip(self.log_magnitudes.iter_mut(), bins) {
+            let magnitude = (re * re + im * im).sqrt();
+            let log_magnitude = (1.0 + 100.0 * magnitude).ln();
+            flux += (log_magnitude - *last).max(0.0);
+            *last = log_magnitude;
+        }
+        flux / self.log_magnitudes.len() as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn onsets(sample_rate: f64, signal: &[f32]) -> Vec<usize> {
+        let mut spectral_flux = SpectralFlux::new(sample_rate);
+        signal.iter()
+            .enumerate()
+            .filter_map(|(i, &v)| if spectral_flux.process(v) { Some(i) } else { None })
+            .collect()
+    }
+
+    fn clicks(len: usize, at: &[usize], amplitude: f32) -> Vec<f32> {
+        let mut signal = vec![0.0; len];
+        for &i in at {
+            signal[i] = amplitude;
+        }
+        signal
+    }
+
+    #[test]
+    fn click_is_detected_at_the_end_of_its_hop() {
+        // the hops end at frames 1023 and 1151
+        assert_eq!(SpectralFlux::new(48000.0).hop_size, 128);
+        assert_eq!(onsets(48000.0, &clicks(4800, &[1000], 1.0)), vec![1023]);
+        assert_eq!(onsets(48000.0, &clicks(4800, &[1030], 1.0)), vec![1151]);
+    }
+
+    #[test]
+    fn click_is_reported_no_later_than_the_latency() {
+        for &sample_rate in [44100.0, 48000.0, 96000.0].iter() {
+            let latency = SpectralFlux::new(sample_rate).latency();
+            for at in 1000..1000 + 2 * latency {
+                let onsets = onsets(sample_rate, &clicks(4 * latency + 1000, &[at], 1.0));
+                assert_eq!(onsets.len(), 1, "{} {}", sample_rate, at);
+                assert!(onsets[0] >= at && onsets[0] - at <= latency,
+                        "{}: click at {}, onset at {}", sample_rate, at, onsets[0]);
+            }
+        }
+    }
+
+    #[test]
+    fn quiet_click_is_below_the_threshold() {
+        assert!(onsets(48000.0, &clicks(4800, &[1000], 1e-4)).is_empty());
+        assert_eq!(onsets(48000.0, &clicks(4800, &[1000], 0.1)).len(), 1);
+    }
+
+    #[test]
+    fn onsets_are_80_ms_apart_at_least() {
+        // 50 ms apart
+        assert_eq!(onsets(48000.0, &clicks(9600, &[1000, 3400], 1.0)), vec![1023]);
+        // 100 ms apart
+        assert_eq!(onsets(48000.0, &clicks(9600, &[1000, 5800], 1.0)), vec![1023, 5887]);
+    }
+}
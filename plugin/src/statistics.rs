@@ -0,0 +1,345 @@
+use urids::protocol::Statistics;
+
+use crate::dsp::{to_dB, Transition, Transitions};
+
+// The statistics of all the hits since the plugin has been instantiated or
+// they have been reset. Unlike the learn mode, they are collected all the
+// time, so they must be cheap to collect.
+
+/// The number of intervals between the hits the tempo is estimated from
+const MAX_INTERVALS: usize = 32;
+
+/// We don't estimate a tempo before we have seen that many intervals.
+const MIN_INTERVALS: usize = 4;
+
+/// The estimated tempo is folded into this range in BPM, as it is ambiguous
+/// by factors of two anyway.
+const MIN_TEMPO: f32 = 80.0;
+const MAX_TEMPO: f32 = 160.0;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    Attack,
+    Sustain,
+    Idle
+}
+
+/// The lengths and the applied gains of the attack or of the sustain parts
+#[derive(Clone, Copy)]
+struct PhaseStatistics {
+    count: u32,
+    total_length: u64,
+
+    gain_frames: u64,
+    gain_sum: f64,
+    /// The linear gain furthest from unity
+    max_gain: f32,
+}
+
+impl Default for PhaseStatistics {
+    fn default() -> Self {
+        PhaseStatistics {
+            count: 0,
+            total_length: 0,
+
+            gain_frames: 0,
+            gain_sum: 0.0,
+            max_gain: 1.0,
+        }
+    }
+}
+
+impl PhaseStatistics {
+    fn add_length(&mut self, length: u64) {
+        self.count += 1;
+        self.total_length += length;
+    }
+
+    fn add_gains(&mut self, gains: &[f32]) {
+        for &gain in gains {
+            self.gain_sum += gain as f64;
+            if gain.max(1.0 / gain) > self.max_gain.max(1.0 / self.max_gain) {
+                self.max_gain = gain;
+            }
+        }
+        self.gain_frames += gains.len() as u64;
+    }
+
+    /// The average length in seconds
+    fn length(&self, sample_rate: f32) -> f32 {
+        if self.count > 0 {
+            self.total_length as f32 / self.count as f32 / sample_rate
+        } else {
+            0.0
+        }
+    }
+
+    /// The average gain in dB
+    fn average_gain(&self) -> f32 {
+        if self.gain_frames > 0 {
+            to_dB(&((self.gain_sum / self.gain_frames as f64) as f32))
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Follows the transitions and the gain of the shaper over the blocks.
+pub struct StatisticsRecorder {
+    sample_rate: f32,
+    frame: u64,
+    phase: Phase,
+    phase_start: u64,
+
+    hit_count: u32,
+    first_hit: Option<u64>,
+    last_hit: Option<u64>,
+    intervals: [u32; MAX_INTERVALS],
+    interval_count: usize,
+    next_interval: usize,
+
+    attack: PhaseStatistics,
+    sustain: PhaseStatistics,
+}
+
+impl StatisticsRecorder {
+    pub fn new(sample_rate: f32) -> Self {
+        StatisticsRecorder {
+            sample_rate,
+            frame: 0,
+            phase: Phase::Idle,
+            phase_start: 0,
+
+            hit_count: 0,
+            first_hit: None,
+            last_hit: None,
+            intervals: [0; MAX_INTERVALS],
+            interval_count: 0,
+            next_interval: 0,
+
+            attack: PhaseStatistics::default(),
+            sustain: PhaseStatistics::default(),
+        }
+    }
+
+    /// Forgets all the hits. A hit going on is not counted anymore.
+    pub fn reset(&mut self) {
+        *self = StatisticsRecorder {
+            frame: self.frame,
+            ..StatisticsRecorder::new(self.sample_rate)
+        };
+    }
+
+    /// Takes the transitions and the linear gain of a block.
+    pub fn record(&mut self, transitions: &Transitions, gain_signal: &[f32]) {
+        let mut start = 0;
+        for &(point, transition) in transitions.events() {
+            let point = point.clamp(start, gain_signal.len());
+            self.add_gains(&gain_signal[start..point]);
+            start = point;
+
+            let frame = self.frame + point as u64;
+            match transition {
+                Transition::Attack => {
+                    // a new hit during the sustain ends the previous one
+                    self.start_hit(frame);
+                    self.enter_phase(Phase::Attack, frame);
+                },
+                Transition::Release => {
+                    if self.phase == Phase::Attack {
+                        self.enter_phase(Phase::Sustain, frame);
+                    }
+                },
+                Transition::Idle => self.enter_phase(Phase::Idle, frame)
+            }
+        }
+        self.add_gains(&gain_signal[start..]);
+
+        self.frame += gain_signal.len() as u64;
+    }
+
+    pub fn statistics(&self) -> Statistics {
+        let hits_per_minute = match (self.first_hit, self.last_hit) {
+            (Some(first), Some(last)) if last > first => {
+                (self.hit_count - 1) as f32 * 60.0 * self.sample_rate / (last - first) as f32
+            }
+            _ => 0.0
+        };
+
+        Statistics {
+            hit_count: self.hit_count,
+            hits_per_minute,
+            tempo: self.tempo(),
+            attack_length: self.attack.length(self.sample_rate),
+            sustain_length: self.sustain.length(self.sample_rate),
+            max_attack_gain: to_dB(&self.attack.max_gain),
+            average_attack_gain: self.attack.average_gain(),
+            max_sustain_gain: to_dB(&self.sustain.max_gain),
+            average_sustain_gain: self.sustain.average_gain(),
+        }
+    }
+
+    fn start_hit(&mut self, frame: u64) {
+        if let Some(last) = self.last_hit {
+            self.intervals[self.next_interval] = (frame - last).min(u32::MAX as u64) as u32;
+            self.next_interval = (self.next_interval + 1) % MAX_INTERVALS;
+            self.interval_count = (self.interval_count + 1).min(MAX_INTERVALS);
+        }
+        self.first_hit.get_or_insert(frame);
+        self.last_hit = Some(frame);
+        self.hit_count += 1;
+    }
+
+    /// Ends the current phase at `frame`.
+    fn enter_phase(&mut self, phase: Phase, frame: u64) {
+        let length = frame - self.phase_start;
+        match self.phase {
+            Phase::Attack => self.attack.add_length(length),
+            Phase::Sustain => self.sustain.add_length(length),
+            Phase::Idle => {}
+        }
+        self.phase = phase;
+        self.phase_start = frame;
+    }
+
+    fn add_gains(&mut self, gains: &[f32]) {
+        match self.phase {
+            Phase::Attack => self.attack.add_gains(gains),
+            Phase::Sustain => self.sustain.add_gains(gains),
+            Phase::Idle => {}
+        }
+    }
+
+    /// The median interval of the last hits as tempo. Sorting a copy of the
+    /// intervals on the stack does not allocate, so this can be called in
+    /// `run()`.
+    fn tempo(&self) -> f32 {
+        if self.interval_count < MIN_INTERVALS {
+            return 0.0;
+        }
+        let mut sorted = self.intervals;
+        let sorted = &mut sorted[..self.interval_count];
+        sorted.sort_unstable();
+        let interval = sorted[self.interval_count / 2];
+        if interval == 0 {
+            return 0.0;
+        }
+
+        let mut tempo = 60.0 * self.sample_rate / interval as f32;
+        while tempo < MIN_TEMPO {
+            tempo *= 2.0;
+        }
+        while tempo >= MAX_TEMPO {
+            tempo /= 2.0;
+        }
+        tempo
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A block with a gain of 2 during the attack and 0.5 during the sustain
+    fn gain_signal(length: usize, events: &[(usize, Transition)]) -> Vec<f32> {
+        let mut gain_signal = vec![1.0; length];
+        for pair in events.windows(2) {
+            let gain = match pair[0].1 {
+                Transition::Attack => 2.0,
+                Transition::Release => 0.5,
+                Transition::Idle => 1.0,
+            };
+            gain_signal[pair[0].0..pair[1].0].iter_mut().for_each(|g| *g = gain);
+        }
+        gain_signal
+    }
+
+    #[test]
+    fn gains_changing_within_the_phases() {
+        let events = [
+            (0, Transition::Attack),
+            (20, Transition::Release),
+            (60, Transition::Idle),
+            (100, Transition::Attack),
+            (110, Transition::Release),
+            (150, Transition::Idle),
+        ];
+        // the gain in the idle phases is not looked at
+        let mut gain_signal = vec![8.0; 200];
+        let segments = [
+            (0, 10, 2.0),
+            (10, 20, 4.0),
+            (20, 40, 1.5),
+            (40, 60, 0.25),
+            (100, 110, 3.0),
+            (110, 150, 0.5),
+        ];
+        for &(start, end, gain) in segments.iter() {
+            gain_signal[start..end].fill(gain);
+        }
+        let mut recorder = StatisticsRecorder::new(1000.0);
+        recorder.record(&Transitions::from_events(&events), &gain_signal);
+
+        let statistics = recorder.statistics();
+        assert_eq!(statistics.hit_count, 2);
+        assert!((statistics.hits_per_minute - 600.0).abs() < 1e-3);
+        assert!((statistics.attack_length - 0.015).abs() < 1e-6);
+        assert!((statistics.sustain_length - 0.04).abs() < 1e-6);
+        assert!((statistics.max_attack_gain - to_dB(&4.0)).abs() < 1e-4);
+        assert!((statistics.average_attack_gain - to_dB(&3.0)).abs() < 1e-4);
+        // the gain furthest from unity, even if it is a cut
+        assert!((statistics.max_sustain_gain - to_dB(&0.25)).abs() < 1e-4);
+        assert!((statistics.average_sustain_gain - to_dB(&0.6875)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn attack_during_sustain_starts_a_new_hit() {
+        let events = [
+            (0, Transition::Attack),
+            (10, Transition::Release),
+            (30, Transition::Attack),
+            (40, Transition::Release),
+        ];
+        let mut recorder = StatisticsRecorder::new(1000.0);
+        recorder.record(&Transitions::from_events(&events), &gain_signal(50, &events));
+        recorder.record(&Transitions::from_events(&[(10, Transition::Idle)]), &[0.5; 50]);
+
+        let statistics = recorder.statistics();
+        assert_eq!(statistics.hit_count, 2);
+        assert!((statistics.attack_length - 0.01).abs() < 1e-6);
+        assert!((statistics.sustain_length - 0.02).abs() < 1e-6);
+    }
+
+    /// The tempo after hits at 1 kHz `intervals` frames apart
+    fn tempo(intervals: &[usize]) -> f32 {
+        let mut recorder = StatisticsRecorder::new(1000.0);
+        for &interval in intervals {
+            recorder.record(&Transitions::from_events(&[(0, Transition::Attack)]), &vec![1.0; interval]);
+        }
+        recorder.record(&Transitions::from_events(&[(0, Transition::Attack)]), &[1.0]);
+        recorder.statistics().tempo
+    }
+
+    #[test]
+    fn no_tempo_from_few_hits() {
+        assert_eq!(tempo(&[500; 3]), 0.0);
+        assert!((tempo(&[500; 4]) - 120.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn tempo_is_folded_into_its_range() {
+        assert!((tempo(&[700; 8]) - 60000.0 / 700.0).abs() < 1e-3);
+        // 240 BPM
+        assert!((tempo(&[250; 8]) - 120.0).abs() < 1e-3);
+        // 40 and 20 BPM
+        assert!((tempo(&[1500; 8]) - 80.0).abs() < 1e-3);
+        assert!((tempo(&[3000; 8]) - 80.0).abs() < 1e-3);
+        // 160 BPM is out of the range
+        assert!((tempo(&[375; 8]) - 80.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn tempo_from_the_median_interval() {
+        assert!((tempo(&[500, 500, 100, 500, 2000]) - 120.0).abs() < 1e-3);
+    }
+}
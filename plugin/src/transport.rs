@@ -0,0 +1,252 @@
+pub const NOTE_VALUES: [f32; 6] = [1./32., 1./16., 1./8., 1./4., 1./2., 1.];
+
+pub struct Transport {
+    sample_rate: f32,
+
+    beats_per_minute: f32,
+    beat_unit: f32,
+    beats_per_bar: f32,
+    speed: f32,
+
+    bar_beat: Option<f64>,
+}
+
+impl Transport {
+    pub fn new(sample_rate: f32) -> Self {
+        Transport {
+            sample_rate,
+            beats_per_minute: 0.0,
+            beat_unit: 4.0,
+            beats_per_bar: 4.0,
+            speed: 0.0,
+            bar_beat: None,
+        }
+    }
+
+    /// Reads a `time:Position` object that the host sent `frame_offset`
+    /// frames into the current block.
+    pub(crate) fn update(&mut self,
+                         object_reader: lv2_atom::object::ObjectReader,
+                         urids: &urids::URIDs,
+                         frame_offset: i64) {
+        let mut bar_beat = None;
+        for (header, atom) in object_reader {
+            if header.key == urids.time.beats_per_minute {
+                if let Some(bpm) = atom.read(urids.atom.float, ()) {
+                    self.beats_per_minute = bpm;
+                }
+            } else if header.key == urids.time.beat_unit {
+                if let Some(beat_unit) = atom.read(urids.atom.int, ()) {
+                    self.beat_unit = beat_unit as f32;
+                }
+            } else if header.key == urids.time.beats_per_bar {
+                if let Some(beats_per_bar) = atom.read(urids.atom.float, ()) {
+                    self.beats_per_bar = beats_per_bar;
+                }
+            } else if header.key == urids.time.speed {
+                if let Some(speed) = atom.read(urids.atom.float, ()) {
+                    self.speed = speed;
+                }
+            } else if header.key == urids.time.bar_beat {
+                bar_beat = atom.read(urids.atom.float, ());
+            }
+        }
+
+        if let Some(bar_beat) = bar_beat {
+            // we process the events at the beginning of the block, so we
+            // move the position back to where the block started
+            self.bar_beat = Some(bar_beat as f64 - frame_offset as f64 * self.beats_per_frame());
+            self.wrap_bar_beat();
+        }
+    }
+
+    fn beats_per_frame(&self) -> f64 {
+        (self.speed * self.beats_per_minute / (60.0 * self.sample_rate)) as f64
+    }
+
+    fn wrap_bar_beat(&mut self) {
+        let beats_per_bar = self.beats_per_bar as f64;
+        if beats_per_bar > 0.0 {
+            self.bar_beat = self.bar_beat.map(|bb| bb.rem_euclid(beats_per_bar));
+        }
+    }
+
+    pub fn advance(&mut self, frames: usize) {
+        let beats_per_frame = self.beats_per_frame();
+        if let Some(bar_beat) = self.bar_beat.as_mut() {
+            *bar_beat += frames as f64 * beats_per_frame;
+        }
+        self.wrap_bar_beat();
+    }
+
+    /// The step of the bar divided in `steps` steps we are in `frame` frames
+    /// after the beginning of the block, before it if negative, or `None` if
+    /// the host did not tell us the position yet or the transport is stopped.
+    pub fn step_at(&self, steps: u32, frame: i64) -> Option<u32> {
+        if self.beats_per_bar <= 0.0 || self.speed == 0.0 {
+            return None;
+        }
+        let beats_per_bar = self.beats_per_bar as f64;
+        self.bar_beat.map(|bb| {
+            let bb = (bb + frame as f64 * self.beats_per_frame()).rem_euclid(beats_per_bar);
+            let step = (bb / beats_per_bar * steps as f64).floor() as u32;
+            step.min(steps - 1)
+        })
+    }
+
+    /// Length of the note value `note` (fraction of a whole note) in seconds
+    /// or `None` if the host did not tell us the tempo yet.
+    pub fn note_length(&self, note: f32) -> Option<f32> {
+        if self.beats_per_minute > 0.0 {
+            Some(note * self.beat_unit * 60.0 / self.beats_per_minute)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+impl Transport {
+    /// A transport in 4/4 at `bar_beat`
+    pub(crate) fn at_position(sample_rate: f32, beats_per_minute: f32, speed: f32, bar_beat: f64) -> Self {
+        Transport {
+            beats_per_minute,
+            speed,
+            bar_beat: Some(bar_beat),
+            ..Transport::new(sample_rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lv2::prelude::*;
+    use lv2_atom::object::ObjectWriter;
+    use lv2_atom::space::RootMutSpace;
+    use urid::mapper::HashURIDMapper;
+
+    /// Lets `transport` read a `time:Position` object with the properties
+    /// written by `write_properties`
+    fn update(transport: &mut Transport,
+              frame_offset: i64,
+              write_properties: impl FnOnce(&urids::URIDs, &mut ObjectWriter) -> Option<()>) {
+        let map = HashURIDMapper::new();
+        let urids = urids::URIDs::from_map(&map).unwrap();
+        let mut raw_space: Box<[u8]> = vec![0; 256].into_boxed_slice();
+        {
+            let mut space = RootMutSpace::new(raw_space.as_mut());
+            let header = ObjectHeader {
+                id: None,
+                otype: urids.time.position_class.into_general(),
+            };
+            let mut object_writer = (&mut space as &mut dyn MutSpace)
+                .init(urids.atom.object, header)
+                .unwrap();
+            write_properties(&urids, &mut object_writer).unwrap();
+        }
+        let (_, object_reader) = UnidentifiedAtom::new(Space::from_slice(raw_space.as_ref()))
+            .read(urids.atom.object, ())
+            .unwrap();
+        transport.update(object_reader, &urids, frame_offset);
+    }
+
+    #[test]
+    fn position_from_the_host() {
+        let mut transport = Transport::new(48000.0);
+        update(&mut transport, 0, |urids, object_writer| {
+            object_writer.init(urids.time.beats_per_minute, urids.atom.float, 120.0)?;
+            object_writer.init(urids.time.beat_unit, urids.atom.int, 8)?;
+            object_writer.init(urids.time.beats_per_bar, urids.atom.float, 3.0)?;
+            object_writer.init(urids.time.speed, urids.atom.float, 1.0)?;
+            object_writer.init(urids.time.bar_beat, urids.atom.float, 2.5)?;
+            Some(())
+        });
+        assert_eq!(transport.step_at(3, 0), Some(2));
+        // in 3/8 a quarter note is two beats
+        assert_eq!(transport.note_length(1. / 4.), Some(1.0));
+    }
+
+    #[test]
+    fn position_is_moved_back_to_the_block_start() {
+        // half a beat later at 120 BPM
+        let mut transport = Transport::at_position(48000.0, 120.0, 1.0, 0.0);
+        update(&mut transport, 12000, |urids, object_writer| {
+            object_writer.init(urids.time.bar_beat, urids.atom.float, 1.3)?;
+            Some(())
+        });
+        assert_eq!(transport.step_at(8, 0), Some(1));
+        assert_eq!(transport.step_at(4, 0), Some(0));
+
+        // back into the last bar
+        update(&mut transport, 12000, |urids, object_writer| {
+            object_writer.init(urids.time.bar_beat, urids.atom.float, 0.1)?;
+            Some(())
+        });
+        assert_eq!(transport.step_at(4, 0), Some(3));
+    }
+
+    #[test]
+    fn missing_properties_are_kept() {
+        let mut transport = Transport::at_position(48000.0, 120.0, 1.0, 1.3);
+        update(&mut transport, 0, |urids, object_writer| {
+            object_writer.init(urids.time.speed, urids.atom.float, 0.0)?;
+            Some(())
+        });
+        assert_eq!(transport.step_at(4, 0), None);
+        assert_eq!(transport.note_length(1. / 4.), Some(0.5));
+
+        update(&mut transport, 0, |urids, object_writer| {
+            object_writer.init(urids.time.speed, urids.atom.float, 1.0)?;
+            Some(())
+        });
+        assert_eq!(transport.step_at(4, 0), Some(1));
+    }
+
+    #[test]
+    fn note_length_without_tempo() {
+        assert_eq!(Transport::new(48000.0).note_length(1. / 4.), None);
+    }
+
+    #[test]
+    fn note_lengths() {
+        let transport = Transport::at_position(48000.0, 120.0, 1.0, 0.0);
+        assert_eq!(transport.note_length(1. / 16.), Some(0.125));
+        assert_eq!(transport.note_length(1. / 4.), Some(0.5));
+        assert_eq!(transport.note_length(1.), Some(2.0));
+    }
+
+    #[test]
+    fn no_step_without_position() {
+        let transport = Transport::new(48000.0);
+        assert_eq!(transport.step_at(16, 0), None);
+    }
+
+    #[test]
+    fn no_step_while_stopped() {
+        let transport = Transport::at_position(48000.0, 120.0, 0.0, 1.0);
+        assert_eq!(transport.step_at(16, 0), None);
+    }
+
+    #[test]
+    fn steps_of_the_bar() {
+        // a sixteenth at 120 BPM is 6000 frames long
+        let transport = Transport::at_position(48000.0, 120.0, 1.0, 0.0);
+        assert_eq!(transport.step_at(16, 0), Some(0));
+        assert_eq!(transport.step_at(16, 5999), Some(0));
+        assert_eq!(transport.step_at(16, 6001), Some(1));
+        assert_eq!(transport.step_at(4, 47999), Some(1));
+        assert_eq!(transport.step_at(16, 95999), Some(15));
+        // the next bar
+        assert_eq!(transport.step_at(16, 96001), Some(0));
+    }
+
+    #[test]
+    fn steps_after_advance() {
+        let mut transport = Transport::at_position(48000.0, 120.0, 1.0, 3.6);
+        assert_eq!(transport.step_at(4, 0), Some(3));
+        transport.advance(24000);
+        assert_eq!(transport.step_at(4, 0), Some(0));
+        assert_eq!(transport.step_at(8, 0), Some(1));
+    }
+}
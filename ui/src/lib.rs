@@ -1,4 +1,6 @@
+use std::collections::VecDeque;
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 #[macro_use]
 extern crate cascade;
@@ -7,6 +9,10 @@ extern crate cascade;
 
 use lv2::prelude::*;
 
+use urids::protocol::{buckets, PluginMessage, Statistics, UIMessage,
+                      CAPABILITY_INSPECTOR, CAPABILITY_LEARN, PROTOCOL_VERSION};
+use urids::shape::{reshape, shape_exponent};
+
 use pugl_ui as pugl;
 use pugl_ui::layout::stacklayout;
 use pugl_ui::widget;
@@ -32,27 +38,169 @@ struct UIPorts {
     gain_release: UIControlPort,
     outgain: UIControlPort,
     mix: UIControlPort,
+    sustain_sync: UIControlPort,
+    sustain_note: UIControlPort,
+    grid_enable: UIControlPort,
+    grid_steps: UIControlPort,
+    grid_mask: UIControlPort,
+    freewheel: UIControlPort,
+    trigger_source: UIControlPort,
+    onset_detector: UIControlPort,
+    adaptive_threshold: UIControlPort,
+    threshold_margin: UIControlPort,
+    attack_width: UIControlPort,
+    sustain_width: UIControlPort,
+    attack_tone: UIControlPort,
+    sustain_tone: UIControlPort,
+    attack_drive: UIControlPort,
+    saturation: UIControlPort,
+    attack_shape: UIControlPort,
+    sustain_shape: UIControlPort,
+    learn: UIControlPort,
+    inspector: UIControlPort,
+    channel_view: UIControlPort,
     control: UIAtomPort,
     notify: UIAtomPort
 }
 
 #[derive(Clone, Copy)]
 struct State {
+    /// No config has been received from the plugin recently.
+    waiting: bool,
+    /// The protocol version of the plugin if it differs from ours
+    version_mismatch: Option<u32>,
+    /// The capabilities the plugin has announced
+    capabilities: u32,
+    inspector: bool,
+    learn: bool,
+    /// The attack and the sustain smooth last suggested by the learn mode,
+    /// until they are applied
+    suggestion: Option<(f32, f32)>,
+    /// The latest statistics of the hits sent by the plugin
+    statistics: Option<Statistics>,
     enabled: bool,
     display_time: f64,
-    attack_point: Option<usize>,
-    release_point: Option<usize>,
-    idle_point: Option<usize>
+    /// The resolution of the received audio data in buckets per second
+    bucket_rate: f64,
+    /// The number of frames per bucket
+    bucket_size: u64,
+    /// The transitions as absolute frames
+    attack_point: Option<u64>,
+    release_point: Option<u64>,
+    idle_point: Option<u64>,
+    attack_boost: f64,
+    sustain_boost: f64,
+    attack_shape: f64,
+    sustain_shape: f64
+}
+
+/// The names of the values of the channel view port
+const CHANNEL_VIEWS: [&str; 5] = ["Max", "Left", "Right", "Mid", "Side"];
+
+/// The time shown before an attack in seconds
+const PREROLL_TIME: f64 = 0.01;
+
+/// The plugin repeats its config every second. If it has not done so for
+/// that long, we consider it gone.
+const CONFIG_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How often we ask for the config while waiting for the plugin
+const CONFIG_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The update rate we tell the plugin if the host does not tell us
+const DEFAULT_UPDATE_RATE: f32 = 25.0;
+
+/// One bucket of the signals shown by the oscilloscope, each as minimum and
+/// maximum in dB
+#[derive(Clone, Copy)]
+struct Bucket {
+    gain: (f32, f32),
+    input: (f32, f32),
+    output: (f32, f32),
+    threshold: Option<(f32, f32)>,
+    envelopes: Option<Envelopes>,
+}
+
+impl Bucket {
+    /// Stands in for buckets that got lost on the way
+    const SILENCE: Bucket = Bucket {
+        gain: (0.0, 0.0),
+        input: (-160.0, -160.0),
+        output: (-160.0, -160.0),
+        threshold: None,
+        envelopes: None,
+    };
+}
+
+/// The internal envelopes of the plugin's shaper in one bucket, only sent in
+/// inspector mode
+#[derive(Clone, Copy)]
+struct Envelopes {
+    attack_fast: (f32, f32),
+    attack_slow: (f32, f32),
+    release_fast: (f32, f32),
+    release_slow: (f32, f32),
+    beat_level: (f32, f32),
+    pregain: (f32, f32),
+}
+
+#[derive(Default)]
+struct Signals {
+    /// The absolute frame the first bucket starts at
+    start_frame: u64,
+    buckets: Vec<Bucket>,
+}
+
+impl Signals {
+    /// Appends the bucket starting at the absolute frame `frame` unless
+    /// `max_len` buckets are already there. Buckets that did not arrive are
+    /// replaced by silence, so that the buckets stay aligned to the frames.
+    fn append(&mut self, frame: u64, bucket: Bucket, bucket_size: u64, max_len: usize) {
+        if self.buckets.is_empty() {
+            self.start_frame = frame;
+        }
+        let next_frame = self.start_frame + self.buckets.len() as u64 * bucket_size;
+        if frame < next_frame {
+            return;
+        }
+        let missing = (((frame - next_frame) / bucket_size) as usize)
+            .min(max_len.saturating_sub(self.buckets.len()));
+        self.buckets.extend(std::iter::repeat(Bucket::SILENCE).take(missing));
+        if self.buckets.len() < max_len {
+            self.buckets.push(bucket);
+        }
+    }
+
+    /// The position of the absolute frame `frame` in buckets
+    fn position(&self, frame: u64, bucket_size: u64) -> Option<f64> {
+        if frame < self.start_frame {
+            return None;
+        }
+        Some((frame - self.start_frame) as f64 / bucket_size as f64)
+    }
 }
 
 impl Default for State {
     fn default() -> Self {
         State {
+            waiting: true,
+            version_mismatch: None,
+            capabilities: 0,
+            inspector: false,
+            learn: false,
+            suggestion: None,
+            statistics: None,
             enabled: true,
             display_time: 0.25,
+            bucket_rate: 0.0,
+            bucket_size: 1,
             attack_point: None,
             release_point: None,
-            idle_point: None
+            idle_point: None,
+            attack_boost: 0.0,
+            sustain_boost: 0.0,
+            attack_shape: 0.0,
+            sustain_shape: 0.0
         }
     }
 }
@@ -65,6 +213,10 @@ struct EnvolvigoUI {
 
     enabled_button: widget::WidgetHandle<jilar::Button>,
     use_sidechain_button: widget::WidgetHandle<jilar::Button>,
+    learn_button: widget::WidgetHandle<jilar::Button>,
+    apply_suggestion_button: widget::WidgetHandle<jilar::Button>,
+    inspector_button: widget::WidgetHandle<jilar::Button>,
+    reset_statistics_button: widget::WidgetHandle<jilar::Button>,
 
     attack_boost_dial: widget::WidgetHandle<jilar::Dial<jilar::dial::LinearScale>>,
     attack_smooth_dial: widget::WidgetHandle<jilar::Dial<jilar::dial::LogScale>>,
@@ -74,24 +226,37 @@ struct EnvolvigoUI {
 
     outgain_dial: widget::WidgetHandle<jilar::Dial<jilar::dial::LinearScale>>,
     mix_dial: widget::WidgetHandle<jilar::Dial<jilar::dial::LinearScale>>,
+    channel_view_dial: widget::WidgetHandle<jilar::Dial<jilar::dial::LinearScale>>,
 
     osci: widget::WidgetHandle<jilar::Osci>,
 
+    sc_meter: widget::WidgetHandle<jilar::Meter>,
+    /// The left channel or the maximum of all channels if not stereo
     in_meter: widget::WidgetHandle<jilar::Meter>,
+    in_right_meter: widget::WidgetHandle<jilar::Meter>,
     out_meter: widget::WidgetHandle<jilar::Meter>,
+    out_right_meter: widget::WidgetHandle<jilar::Meter>,
     meter_damping_coeff: f32,
 
     ports: UIPorts,
     write_handle: PluginPortWriteHandle,
 
-    input_signal: Arc<RwLock<Vec<f32>>>,
-    output_signal: Arc<RwLock<Vec<f32>>>,
-    gain_signal: Arc<RwLock<Vec<f32>>>,
+    signals: Arc<RwLock<Signals>>,
+    /// The last buckets, to show what happened before an attack
+    preroll: VecDeque<(u64, Bucket)>,
 
     state: Arc<RwLock<State>>,
+    osci_width: Arc<RwLock<f64>>,
+    requested_resolution: f32,
+    /// How often per second the host updates us
+    update_rate: f32,
 
     sample_rate: f64,
-    drawing_task_submitted: bool,
+    last_config: Option<Instant>,
+    last_config_request: Option<Instant>,
+    /// An overflow of the notify buffer has been logged since the UI has
+    /// been switched on.
+    overflow_logged: bool,
 
     urids: urids::URIDs
 }
@@ -121,6 +286,10 @@ impl EnvolvigoUI {
 
         let enabled_button = ui.new_widget(jilar::Button::new_toggle_button("Enabled", 2./3.));
         let use_sidechain_button = ui.new_widget(jilar::Button::new_toggle_button("Sidechain", 2./3.));
+        let learn_button = ui.new_widget(jilar::Button::new_toggle_button("Learn", 2./3.));
+        let apply_suggestion_button = ui.new_widget(jilar::Button::new_toggle_button("Apply", 2./3.));
+        let inspector_button = ui.new_widget(jilar::Button::new_toggle_button("Inspector", 2./3.));
+        let reset_statistics_button = ui.new_widget(jilar::Button::new_toggle_button("Reset stats", 2./3.));
 
         let attack_boost_dial = ui.new_widget( cascade! {
             jilar::Dial::new(-30.0, 30.0, 12);
@@ -178,6 +347,15 @@ impl EnvolvigoUI {
             ..set_formater(&|v| format!("{:.0} %", v*100.0));
         });
 
+        let channel_view_dial = ui.new_widget( cascade! {
+            jilar::Dial::new(0.0, 4.0, 4);
+            ..set_plate_draw( &|d: &jilar::Dial<jilar::dial::LinearScale>, cr: &cairo::Context| {
+                jilar::dial::draw_angle_tics(d, cr, 5)
+            });
+            ..set_default_value(0.0);
+            ..set_formater(&|v| CHANNEL_VIEWS[(v.round().max(0.0) as usize).min(CHANNEL_VIEWS.len() - 1)].to_string());
+        });
+
         let osci = ui.new_widget( cascade! {
             jilar::Osci::new();
             ..set_level_range(-72.0, 12.0);
@@ -186,8 +364,11 @@ impl EnvolvigoUI {
             ..linear_major_yticks(12);
         });
 
+        let sc_meter = ui.new_widget(jilar::Meter::new(1./update_rate));
         let in_meter = ui.new_widget(jilar::Meter::new(1./update_rate));
+        let in_right_meter = ui.new_widget(jilar::Meter::new(1./update_rate));
         let out_meter = ui.new_widget(jilar::Meter::new(1./update_rate));
+        let out_right_meter = ui.new_widget(jilar::Meter::new(1./update_rate));
 
         ui.layouter(ui.root_layout()).set_padding(5.0);
         ui.pack_to_layout(osci, ui.root_layout(), stacklayout::StackDirection::Back);
@@ -196,13 +377,17 @@ impl EnvolvigoUI {
         ui.widget(controls_layout.widget()).lock_height();
         ui.pack_to_layout(controls_layout.widget(), ui.root_layout(), stacklayout::StackDirection::Back);
 
-        // Layout "Enabled" and "Sidechain"
+        // Layout "Enabled", "Sidechain", "Learn" and "Inspector"
         let vl = ui.new_layouter::<stacklayout::VerticalLayouter>();
         ui.pack_to_layout(vl.widget(), controls_layout, stacklayout::StackDirection::Back);
 
         ui.add_spacer(vl, stacklayout::StackDirection::Back);
         ui.pack_to_layout(enabled_button, vl, stacklayout::StackDirection::Back);
         ui.pack_to_layout(use_sidechain_button, vl, stacklayout::StackDirection::Back);
+        ui.pack_to_layout(learn_button, vl, stacklayout::StackDirection::Back);
+        ui.pack_to_layout(apply_suggestion_button, vl, stacklayout::StackDirection::Back);
+        ui.pack_to_layout(inspector_button, vl, stacklayout::StackDirection::Back);
+        ui.pack_to_layout(reset_statistics_button, vl, stacklayout::StackDirection::Back);
         ui.add_spacer(vl, stacklayout::StackDirection::Back);
 
         ui.add_spacer(controls_layout, stacklayout::StackDirection::Back);
@@ -311,6 +496,43 @@ impl EnvolvigoUI {
 
         ui.add_spacer(controls_layout, stacklayout::StackDirection::Back);
 
+        // Layout "Channel view dial"
+        let sect_layout = ui.new_layouter::<stacklayout::VerticalLayouter>();
+        ui.pack_to_layout(sect_layout.widget(), controls_layout, stacklayout::StackDirection::Back);
+
+        ui.add_spacer(sect_layout, stacklayout::StackDirection::Back);
+
+        let hl = ui.new_layouter::<stacklayout::HorizontalLayouter>();
+        ui.pack_to_layout(hl.widget(), sect_layout, stacklayout::StackDirection::Back);
+        ui.add_spacer(hl, stacklayout::StackDirection::Back);
+        ui.pack_to_layout(channel_view_dial, hl, stacklayout::StackDirection::Back);
+        ui.add_spacer(hl, stacklayout::StackDirection::Back);
+
+        let hl = ui.new_layouter::<stacklayout::HorizontalLayouter>();
+        ui.pack_to_layout(hl.widget(), sect_layout, stacklayout::StackDirection::Back);
+        ui.add_spacer(hl, stacklayout::StackDirection::Back);
+        let lb = ui.new_widget(jilar::Label::new("Channels"));
+        ui.pack_to_layout(lb, hl, stacklayout::StackDirection::Back);
+        ui.add_spacer(hl, stacklayout::StackDirection::Back);
+
+        ui.add_spacer(controls_layout, stacklayout::StackDirection::Back);
+
+        let sect_layout = ui.new_layouter::<stacklayout::VerticalLayouter>();
+        ui.pack_to_layout(sect_layout.widget(), controls_layout, stacklayout::StackDirection::Back);
+
+        let hl = ui.new_layouter::<stacklayout::HorizontalLayouter>();
+        ui.pack_to_layout(hl.widget(), sect_layout, stacklayout::StackDirection::Back);
+        ui.add_spacer(hl, stacklayout::StackDirection::Back);
+        ui.pack_to_layout(sc_meter, hl, stacklayout::StackDirection::Back);
+        ui.add_spacer(hl, stacklayout::StackDirection::Back);
+
+        let hl = ui.new_layouter::<stacklayout::HorizontalLayouter>();
+        ui.pack_to_layout(hl.widget(), sect_layout, stacklayout::StackDirection::Back);
+        ui.add_spacer(hl, stacklayout::StackDirection::Back);
+        let lb = ui.new_widget(jilar::Label::new("SC"));
+        ui.pack_to_layout(lb, hl, stacklayout::StackDirection::Back);
+        ui.add_spacer(hl, stacklayout::StackDirection::Back);
+
         let sect_layout = ui.new_layouter::<stacklayout::VerticalLayouter>();
         ui.pack_to_layout(sect_layout.widget(), controls_layout, stacklayout::StackDirection::Back);
 
@@ -318,6 +540,7 @@ impl EnvolvigoUI {
         ui.pack_to_layout(hl.widget(), sect_layout, stacklayout::StackDirection::Back);
         ui.add_spacer(hl, stacklayout::StackDirection::Back);
         ui.pack_to_layout(in_meter, hl, stacklayout::StackDirection::Back);
+        ui.pack_to_layout(in_right_meter, hl, stacklayout::StackDirection::Back);
         ui.add_spacer(hl, stacklayout::StackDirection::Back);
 
         let hl = ui.new_layouter::<stacklayout::HorizontalLayouter>();
@@ -334,6 +557,7 @@ impl EnvolvigoUI {
         ui.pack_to_layout(hl.widget(), sect_layout, stacklayout::StackDirection::Back);
         ui.add_spacer(hl, stacklayout::StackDirection::Back);
         ui.pack_to_layout(out_meter, hl, stacklayout::StackDirection::Back);
+        ui.pack_to_layout(out_right_meter, hl, stacklayout::StackDirection::Back);
         ui.add_spacer(hl, stacklayout::StackDirection::Back);
 
         let hl = ui.new_layouter::<stacklayout::HorizontalLayouter>();
@@ -356,24 +580,36 @@ impl EnvolvigoUI {
             view,
             enabled_button,
             use_sidechain_button,
+            learn_button,
+            apply_suggestion_button,
+            inspector_button,
+            reset_statistics_button,
             attack_boost_dial,
             sustain_boost_dial,
             attack_smooth_dial,
             sustain_smooth_dial,
             outgain_dial,
             mix_dial,
+            channel_view_dial,
             osci,
+            sc_meter,
             in_meter,
+            in_right_meter,
             out_meter,
+            out_right_meter,
             meter_damping_coeff: 1.0,
             ports,
             write_handle,
-            input_signal: Arc::new(RwLock::new(Vec::new())),
-            output_signal: Arc::new(RwLock::new(Vec::new())),
-            gain_signal: Arc::new(RwLock::new(Vec::new())),
+            signals: Arc::new(RwLock::new(Signals::default())),
+            preroll: VecDeque::new(),
             state: Arc::new(RwLock::new(State::default())),
+            osci_width: Arc::new(RwLock::new(0.0)),
+            requested_resolution: 0.0,
+            update_rate: if update_rate > 0.0 { update_rate as f32 } else { DEFAULT_UPDATE_RATE },
             sample_rate: 0.0,
-            drawing_task_submitted: false,
+            last_config: None,
+            last_config_request: None,
+            overflow_logged: false,
             urids
         })
     }
@@ -390,22 +626,90 @@ impl EnvolvigoUI {
         println!("ui_enable");
         self.ports.control.init(
             self.urids.atom.object,
-            ObjectHeader {
-                id: None,
-                otype: self.urids.ui_on.into_general()
-            });
+            UIMessage::On.header(&self.urids));
+        self.write_handle.write_port(&self.ports.control);
+        self.requested_resolution = 0.0;
+        self.last_config_request = Some(Instant::now());
+        self.overflow_logged = false;
+        self.send_update_rate();
+    }
+
+    fn send_config_request(&mut self) {
+        self.ports.control.init(
+            self.urids.atom.object,
+            UIMessage::RequestConfig.header(&self.urids));
+        self.write_handle.write_port(&self.ports.control);
+        self.last_config_request = Some(Instant::now());
+        // might have got lost like the config
+        self.send_update_rate();
+    }
+
+    /// Asks the plugin not to notify us more often than we are updated.
+    fn send_update_rate(&mut self) {
+        let message = UIMessage::UpdateRate { updates_per_second: self.update_rate };
+        if let Some(mut object_writer) = self.ports.control.init(
+            self.urids.atom.object,
+            message.header(&self.urids)) {
+            message.write_properties(&self.urids, &mut object_writer);
+        }
+        self.write_handle.write_port(&self.ports.control);
+    }
+
+    fn send_statistics_reset(&mut self) {
+        self.ports.control.init(
+            self.urids.atom.object,
+            UIMessage::ResetStatistics.header(&self.urids));
         self.write_handle.write_port(&self.ports.control);
     }
 
+    fn submit_draw_task(&mut self) {
+        let signals = self.signals.clone();
+        let state = self.state.clone();
+        let osci_width = self.osci_width.clone();
+        self.widget(self.osci).submit_draw_task(
+            Box::new(OsciDrawings {
+                signals,
+                state,
+                osci_width,
+                disable_alpha: 1.0,
+            })
+        );
+    }
+
     fn send_ui_disable(&mut self) {
         println!("ui_disable");
         self.ports.control.init(
             self.urids.atom.object,
-            ObjectHeader {
-                id: None,
-                otype: self.urids.ui_off.into_general()
-            });
+            UIMessage::Off.header(&self.urids));
+        self.write_handle.write_port(&self.ports.control);
+    }
+
+    /// Sets the smooth knobs to the last suggestion of the learn mode.
+    fn apply_suggestion(&mut self) {
+        let suggestion = self.state.write().unwrap().suggestion.take();
+        if let Some((attack_smooth, sustain_smooth)) = suggestion {
+            self.widget(self.attack_smooth_dial).set_value(attack_smooth as f64);
+            self.ports.attack_smooth.set_value(attack_smooth);
+            self.write_handle.write_port(&self.ports.attack_smooth);
+
+            self.widget(self.sustain_smooth_dial).set_value(sustain_smooth as f64);
+            self.ports.sustain_smooth.set_value(sustain_smooth);
+            self.write_handle.write_port(&self.ports.sustain_smooth);
+
+            self.widget(self.osci).ask_for_repaint();
+        }
+    }
+
+    /// Asks the plugin for about one bucket of audio data per pixel.
+    fn send_resolution(&mut self, buckets_per_second: f32) {
+        let message = UIMessage::Resolution { buckets_per_second };
+        if let Some(mut object_writer) = self.ports.control.init(
+            self.urids.atom.object,
+            message.header(&self.urids)) {
+            message.write_properties(&self.urids, &mut object_writer);
+        }
         self.write_handle.write_port(&self.ports.control);
+        self.requested_resolution = buckets_per_second;
     }
 }
 
@@ -419,6 +723,7 @@ impl PluginUI for EnvolvigoUI {
            parent_window: *mut std::ffi::c_void,
            write_handle: PluginPortWriteHandle) -> Option<Self> {
         let mut ui = Self::new(features, parent_window, write_handle)?;
+        ui.submit_draw_task();
         ui.send_ui_enable();
         Some(ui)
     }
@@ -455,6 +760,23 @@ impl PluginUI for EnvolvigoUI {
             self.ports.use_sidechain.set_value(if ts { 1.0 } else { 0.0 });
             self.write_handle.write_port(&self.ports.use_sidechain);
         }
+        if let Some(ts) = self.widget(self.learn_button).changed_toggle_state() {
+            self.ports.learn.set_value(if ts { 1.0 } else { 0.0 });
+            self.write_handle.write_port(&self.ports.learn);
+        }
+        if let Some(ts) = self.widget(self.inspector_button).changed_toggle_state() {
+            self.ports.inspector.set_value(if ts { 1.0 } else { 0.0 });
+            self.write_handle.write_port(&self.ports.inspector);
+        }
+        // the apply and the reset button are not ports, they just spring back
+        if let Some(true) = self.widget(self.apply_suggestion_button).changed_toggle_state() {
+            self.apply_suggestion();
+            self.widget(self.apply_suggestion_button).set_toggle_state(false);
+        }
+        if let Some(true) = self.widget(self.reset_statistics_button).changed_toggle_state() {
+            self.send_statistics_reset();
+            self.widget(self.reset_statistics_button).set_toggle_state(false);
+        }
 
         if let Some(v) = self.widget(self.attack_boost_dial).changed_value() {
             self.ports.attack_boost.set_value(v as f32);
@@ -482,6 +804,10 @@ impl PluginUI for EnvolvigoUI {
             self.ports.mix.set_value(v as f32);
             self.write_handle.write_port(&self.ports.mix);
         }
+        if let Some(v) = self.widget(self.channel_view_dial).changed_value() {
+            self.ports.channel_view.set_value(v.round() as f32);
+            self.write_handle.write_port(&self.ports.channel_view);
+        }
 
         self.update();
 
@@ -493,6 +819,8 @@ impl PluginUI for EnvolvigoUI {
             *self.state.read().unwrap()
         };
 
+        let mut osci_repaint = false;
+
         if let Some(v) = self.ports.enabled.changed_value() {
             let enabled = v > 0.5;
             state.enabled = enabled;
@@ -501,8 +829,18 @@ impl PluginUI for EnvolvigoUI {
         if let Some(v) = self.ports.use_sidechain.changed_value() {
             self.widget(self.use_sidechain_button).set_toggle_state(v > 0.5);
         }
+        if let Some(v) = self.ports.learn.changed_value() {
+            state.learn = v > 0.5;
+            self.widget(self.learn_button).set_toggle_state(v > 0.5);
+            osci_repaint = true;
+        }
+        if let Some(v) = self.ports.inspector.changed_value() {
+            state.inspector = v > 0.5;
+            self.widget(self.inspector_button).set_toggle_state(v > 0.5);
+        }
 
         if let Some(v) = self.ports.attack_boost.changed_value() {
+            state.attack_boost = v as f64;
             self.widget(self.attack_boost_dial).set_value(v as f64);
         }
         if let Some(v) = self.ports.attack_smooth.changed_value() {
@@ -510,6 +848,7 @@ impl PluginUI for EnvolvigoUI {
         }
 
         if let Some(v) = self.ports.sustain_boost.changed_value() {
+            state.sustain_boost = v as f64;
             self.widget(self.sustain_boost_dial).set_value(v as f64);
         }
         if let Some(v) = self.ports.sustain_smooth.changed_value() {
@@ -522,140 +861,215 @@ impl PluginUI for EnvolvigoUI {
         if let Some(v) = self.ports.mix.changed_value() {
             self.widget(self.mix_dial).set_value(v as f64);
         }
+        if let Some(v) = self.ports.channel_view.changed_value() {
+            self.widget(self.channel_view_dial).set_value(v as f64);
+        }
 
-        let mut osci_repaint = false;
-        let mut received_sample_rate = false;
-        let displayed_sample_num = (state.display_time * self.sample_rate).ceil() as usize;
-        let in_peak = self.widget(self.in_meter).level();
-        let mut new_in_peak = -160.0;
-        let out_peak = self.widget(self.out_meter).level();
-        let mut new_out_peak = -160.0;
-        let meter_damping_coeff = self.meter_damping_coeff;
-
-        if let Some((_, object_reader)) = self.ports.notify.read(self.urids.atom.object, ()) {
-            for (header, atom) in object_reader {
-                if header.key == self.urids.parameters.sample_rate  {
-                    if let Some(sr) =  atom.read(self.urids.atom.float, ()) {
-                        self.sample_rate = sr as f64;
-                        self.meter_damping_coeff = 1.0f32 - (-6.28f32 * 50000.0f32/sr).exp();
+        if let Some(v) = self.ports.attack_shape.changed_value() {
+            state.attack_shape = v as f64;
+            osci_repaint = true;
+        }
+        if let Some(v) = self.ports.sustain_shape.changed_value() {
+            state.sustain_shape = v as f64;
+            osci_repaint = true;
+        }
+        let sc_peak = self.widget(self.sc_meter).level();
+        let mut new_sc_peak = -160.0;
+        let in_peaks = [self.widget(self.in_meter).level(), self.widget(self.in_right_meter).level()];
+        let mut new_in_peaks = [-160.0; 2];
+        let out_peaks = [self.widget(self.out_meter).level(), self.widget(self.out_right_meter).level()];
+        let mut new_out_peaks = [-160.0; 2];
+
+        // We only understand the plugin if it speaks our protocol version.
+        let synced = self.sample_rate > 0.0 && state.version_mismatch.is_none();
+
+        if let Some((header, object_reader)) = self.ports.notify.read(self.urids.atom.object, ()) {
+            match PluginMessage::read(&self.urids, &header, object_reader) {
+                Some(PluginMessage::Config { protocol_version, .. }) if protocol_version != PROTOCOL_VERSION => {
+                    if state.version_mismatch != Some(protocol_version) {
+                        eprintln!("plugin speaks protocol version {}, the UI version {}",
+                                  protocol_version, PROTOCOL_VERSION);
+                        state.version_mismatch = Some(protocol_version);
+                        osci_repaint = true;
+                    }
+                    self.last_config = Some(Instant::now());
+                }
+                Some(PluginMessage::Config { sample_rate, capabilities, .. }) => {
+                    if state.version_mismatch.is_some() || capabilities != state.capabilities {
+                        state.version_mismatch = None;
+                        state.capabilities = capabilities;
+                        osci_repaint = true;
+                    }
+                    if sample_rate as f64 != self.sample_rate {
+                        self.sample_rate = sample_rate as f64;
+                        // makes the next audio data recalculate the bucket
+                        // size and the meter damping
+                        state.bucket_rate = 0.0;
+                    }
+                    self.last_config = Some(Instant::now());
+                }
+                Some(PluginMessage::LearnResult { attack_smooth, sustain_smooth }) if synced => {
+                    state.suggestion = Some((attack_smooth, sustain_smooth));
+                    osci_repaint = true;
+                }
+                Some(PluginMessage::State(plugin_state)) if synced => {
+                    if let Some(frame) = plugin_state.attack_point {
+                        state.attack_point = Some(frame);
+                        state.release_point = None;
+                        state.idle_point = None;
+
+                        // the oscilloscope starts again shortly before the attack
+                        let preroll_start = frame.saturating_sub((PREROLL_TIME * self.sample_rate) as u64);
+                        let mut signals = self.signals.write().unwrap();
+                        signals.buckets.clear();
+                        for &(bucket_frame, bucket) in self.preroll.iter() {
+                            if bucket_frame + state.bucket_size > preroll_start {
+                                signals.append(bucket_frame, bucket, state.bucket_size, usize::MAX);
+                            }
+                        }
+                    }
+                    if let Some(frame) = plugin_state.release_point {
+                        state.release_point = Some(frame);
+                    }
+                    if let Some(frame) = plugin_state.idle_point {
+                        state.idle_point = Some(frame);
+                    }
+                }
+                Some(PluginMessage::AudioData(audio_data)) if synced => {
+                    let bucket_rate = audio_data.buckets_per_second as f64;
+                    if bucket_rate != state.bucket_rate {
+                        // the buckets received so far don't fit anymore
+                        self.signals.write().unwrap().buckets.clear();
+                        self.preroll.clear();
+                        state.attack_point = None;
+                        state.release_point = None;
+                        state.idle_point = None;
+                        state.bucket_rate = bucket_rate;
+                        state.bucket_size = ((self.sample_rate / bucket_rate).round() as u64).max(1);
+
+                        let sample_coeff = 1.0f32 - (-6.28f32 * 50000.0f32/self.sample_rate as f32).exp();
+                        self.meter_damping_coeff = sample_coeff.powf(self.sample_rate as f32 / audio_data.buckets_per_second);
                         println!("coeff = {:e}", self.meter_damping_coeff);
-                        received_sample_rate = true;
-                    } else {
-                        eprintln!("expected float for sample rate, got something different");
-                    };
-                } else if header.key == self.urids.attack_point {
-                    if let Some(ap) = atom.read(self.urids.atom.int, ()) {
-                        state.attack_point = Some(ap as usize);
-                        let mut input_signal = self.input_signal.write().unwrap();
-                        let mut output_signal = self.output_signal.write().unwrap();
-                        let mut gain_signal = self.gain_signal.write().unwrap();
-
-                        let cut_samples = input_signal.len() - (0.01 * self.sample_rate).floor() as usize;
-                        if input_signal.len() != gain_signal.len() {
-                            println!("warning: input != gain {} {}", input_signal.len(), gain_signal.len());
+                    }
+                    let meter_damping_coeff = self.meter_damping_coeff;
+                    let displayed_bucket_num = (state.display_time * state.bucket_rate).ceil() as usize;
+
+                    let preroll_len = (PREROLL_TIME * bucket_rate).ceil() as usize + 1;
+                    // The plugin collects the buckets between the updates,
+                    // so they can start before the preroll of an attack.
+                    let preroll_start = state.attack_point
+                        .map_or(0, |frame| frame.saturating_sub((PREROLL_TIME * self.sample_rate) as u64));
+                    let mut signals = self.signals.write().unwrap();
+                    let mut threshold_buckets = audio_data.threshold_signal.map(buckets);
+                    let mut envelope_buckets = audio_data.inspector.map(|inspector| {
+                        buckets(inspector.attack_fast)
+                            .zip(buckets(inspector.attack_slow))
+                            .zip(buckets(inspector.release_fast))
+                            .zip(buckets(inspector.release_slow))
+                            .zip(buckets(inspector.beat_level))
+                            .zip(buckets(inspector.pregain))
+                            .map(|(((((attack_fast, attack_slow), release_fast), release_slow), beat_level), pregain)| {
+                                Envelopes {
+                                    attack_fast,
+                                    attack_slow,
+                                    release_fast,
+                                    release_slow,
+                                    beat_level,
+                                    pregain,
+                                }
+                            })
+                    });
+                    let new_buckets = buckets(audio_data.gain_signal)
+                        .zip(buckets(audio_data.input_signal))
+                        .zip(buckets(audio_data.output_signal));
+                    for (i, ((gain, input), output)) in new_buckets.enumerate() {
+                        let bucket = Bucket {
+                            gain,
+                            input,
+                            output,
+                            threshold: threshold_buckets.as_mut().and_then(Iterator::next),
+                            envelopes: envelope_buckets.as_mut().and_then(Iterator::next),
+                        };
+                        let frame = audio_data.first_frame + i as u64 * state.bucket_size;
+
+                        self.preroll.push_back((frame, bucket));
+                        if self.preroll.len() > preroll_len {
+                            self.preroll.pop_front();
                         }
-
-                        gain_signal.drain(..cut_samples);
-                        input_signal.drain(..cut_samples);
-                        output_signal.drain(..cut_samples);
-                    } else {
-                        eprintln!("expected int for attack point, got something different");
-                    };
-                } else if header.key == self.urids.release_point {
-                    if let Some(p) = atom.read(self.urids.atom.int, ()) {
-                        let input_signal = self.input_signal.read().unwrap();
-                        state.release_point = Some(p as usize + input_signal.len());
-                    } else {
-                        eprintln!("expected int for release point, got something different");
-                    };
-                } else if header.key == self.urids.idle_point {
-                    if let Some(p) = atom.read(self.urids.atom.int, ()) {
-                        let input_signal = self.input_signal.read().unwrap();
-                        state.idle_point = Some(p as usize + input_signal.len());
-                    } else {
-                        eprintln!("expected int for idle point, got something different");
-                    };
-                } else if header.key == self.urids.gain_signal {
-                    if let Some(new_gain_signal) = atom.read(self.urids.atom.vector(), self.urids.atom.float) {
-                        let mut gain_signal = self.gain_signal.write().unwrap();
-
-                        if gain_signal.len() < displayed_sample_num {
-                            gain_signal.extend(new_gain_signal);
+                        if frame + state.bucket_size > preroll_start {
+                            signals.append(frame, bucket, state.bucket_size, displayed_bucket_num);
                         }
-                    } else {
-                        eprintln!("expected vector of floats, got something different");
                     }
-                } else if header.key == self.urids.input_signal {
-                    if let Some(new_input_signal) = atom.read(self.urids.atom.vector(), self.urids.atom.float) {
-                        let mut input_signal = self.input_signal.write().unwrap();
 
-                        if input_signal.len() < displayed_sample_num {
-                            input_signal.extend(new_input_signal);
-                        }
-                        new_in_peak = new_input_signal
-                            .iter()
-                            .fold(in_peak, |a, &v| {
-                                osci_repaint |= v > -72.0;
+                    osci_repaint |= buckets(audio_data.input_signal).any(|(_, v)| v > -72.0);
+
+                    let meter = |peak: f32, signal: &[f32]| {
+                        buckets(signal)
+                            .fold(peak, |a, (_, v)| {
                                 if v >= a {
                                     v
                                 } else {
                                     v + meter_damping_coeff * (a - v)
                                 }
-                            });
-                    } else {
-                        eprintln!("expected vector of floats, got something different");
-                    }
-                } else if header.key == self.urids.output_signal {
-                    if let Some(new_output_signal) = atom.read(self.urids.atom.vector(), self.urids.atom.float) {
-                        let mut output_signal = self.output_signal.write().unwrap();
+                            })
+                    };
 
-                        if output_signal.len() < displayed_sample_num {
-                            output_signal.extend(new_output_signal);
-                        }
-                        new_out_peak = new_output_signal
-                            .iter()
-                            .fold(out_peak, |a, &v| {
-                                osci_repaint |= v > 72.0;
-                                if v > a {
-                                    v
-                                } else {
-                                    v + meter_damping_coeff * (a - v)
-                                }
-                            });
-                    } else {
-                        eprintln!("expected vector of floats, got something different");
+                    if let Some(new_sidechain_signal) = audio_data.sidechain_signal {
+                        new_sc_peak = meter(sc_peak, new_sidechain_signal);
                     }
-                } else {
-                    eprintln!("unknown atom information received");
+
+                    // the meters show both channels of the stereo plugin
+                    // regardless of the channel view
+                    let (input_signals, output_signals) = match audio_data.stereo {
+                        Some(stereo) => ([stereo.input_left, stereo.input_right],
+                                         [stereo.output_left, stereo.output_right]),
+                        None => ([audio_data.input_signal; 2], [audio_data.output_signal; 2]),
+                    };
+                    new_in_peaks = [meter(in_peaks[0], input_signals[0]), meter(in_peaks[1], input_signals[1])];
+                    new_out_peaks = [meter(out_peaks[0], output_signals[0]), meter(out_peaks[1], output_signals[1])];
                 }
+                Some(PluginMessage::Statistics(statistics)) if synced => {
+                    state.statistics = Some(statistics);
+                    osci_repaint = true;
+                }
+                // only once, as it happens all the time with a small buffer
+                Some(PluginMessage::Overflow { dropped_messages }) if synced && !self.overflow_logged => {
+                    eprintln!("notify buffer of the host too small, {} messages dropped", dropped_messages);
+                    self.overflow_logged = true;
+                }
+                // Without the sample rate we can't interpret the frames.
+                // Messages of another protocol version might be
+                // misinterpreted, so we rather ignore them.
+                Some(_) => {}
+                None => eprintln!("unknown atom information received"),
             }
         }
 
-        self.widget(self.in_meter).set_level(new_in_peak);
-        self.widget(self.out_meter).set_level(new_out_peak);
-
-        *self.state.write().unwrap() = state;
-
-        if received_sample_rate && !self.drawing_task_submitted {
-            let input_signal = self.input_signal.clone();
-            let output_signal = self.output_signal.clone();
-            let gain_signal = self.gain_signal.clone();
-            let sample_rate = self.sample_rate;
-            let state = self.state.clone();
-            self.widget(self.osci).submit_draw_task(
-                Box::new(OsciDrawings {
-                    input_signal,
-                    output_signal,
-                    gain_signal,
-                    sample_rate,
-                    state,
-                    disable_alpha: 1.0,
-                })
-            );
-            self.drawing_task_submitted = true;
+        let waiting = self.last_config.map_or(true, |time| time.elapsed() > CONFIG_TIMEOUT);
+        if waiting && self.last_config_request.map_or(true, |time| time.elapsed() > CONFIG_REQUEST_INTERVAL) {
+            self.send_config_request();
+        }
+        if waiting != state.waiting {
+            state.waiting = waiting;
             osci_repaint = true;
         }
 
+        let osci_width = *self.osci_width.read().unwrap();
+        if osci_width > 0.0 {
+            let resolution = (osci_width / state.display_time) as f32;
+            if resolution != self.requested_resolution {
+                self.send_resolution(resolution);
+            }
+        }
+
+        self.widget(self.sc_meter).set_level(new_sc_peak);
+        self.widget(self.in_meter).set_level(new_in_peaks[0]);
+        self.widget(self.in_right_meter).set_level(new_in_peaks[1]);
+        self.widget(self.out_meter).set_level(new_out_peaks[0]);
+        self.widget(self.out_right_meter).set_level(new_out_peaks[1]);
+
+        *self.state.write().unwrap() = state;
+
         if osci_repaint {
             self.widget(self.osci).ask_for_repaint();
         }
@@ -705,20 +1119,117 @@ impl RootWidget {
 }
 
 struct OsciDrawings {
-    input_signal: Arc<RwLock<Vec<f32>>>,
-    output_signal: Arc<RwLock<Vec<f32>>>,
-    gain_signal: Arc<RwLock<Vec<f32>>>,
-    sample_rate: f64,
+    signals: Arc<RwLock<Signals>>,
     state: Arc<RwLock<State>>,
+    /// Tells the UI the width, so that it can ask for a matching resolution
+    osci_width: Arc<RwLock<f64>>,
 
     disable_alpha: f64,
 }
 
+impl OsciDrawings {
+    /// Draws the gain over the detector difference of the attack and the
+    /// sustain into a small box in the upper right corner.
+    fn draw_transfer_curves(&self, state: &State, right: f64, top: f64, cr: &cairo::Context) {
+        const SIZE: f64 = 60.0;
+        const MARGIN: f64 = 8.0;
+        const MAX_DB: f64 = 30.0;
+
+        let left = right - SIZE - MARGIN;
+        let top = top + MARGIN;
+        let zero = top + SIZE / 2.0;
+
+        cr.set_source_rgba(0.4, 0.4, 0.4, 0.6 * self.disable_alpha);
+        cr.set_line_width(0.5);
+        cr.rectangle(left, top, SIZE, SIZE);
+        cr.move_to(left, zero);
+        cr.line_to(left + SIZE, zero);
+        cr.stroke();
+
+        let curves = [
+            (state.attack_boost, state.attack_shape, (1.0, 0.5, 0.0)),
+            (state.sustain_boost, state.sustain_shape, (0.0, 0.8, 1.0))
+        ];
+        for &(boost, shape, (r, g, b)) in curves.iter() {
+            cr.set_source_rgba(r, g, b, self.disable_alpha);
+            cr.set_line_width(1.0);
+            cr.move_to(left, zero);
+            for px in 1..=SIZE as usize {
+                let x = px as f64 / SIZE;
+                let gain = boost * reshape(x, shape_exponent(shape));
+                cr.line_to(left + px as f64, zero - gain.max(-MAX_DB).min(MAX_DB) / MAX_DB * SIZE / 2.0);
+            }
+            cr.stroke();
+        }
+    }
+
+    /// Lists the statistics of the hits below the transfer curves.
+    fn draw_statistics(&self, statistics: &Statistics, right: f64, top: f64, cr: &cairo::Context) {
+        const MARGIN: f64 = 8.0;
+        const LINE_HEIGHT: f64 = 12.0;
+
+        let rate = if statistics.tempo > 0.0 {
+            format!("{:.0}/min, ≈ {:.0} BPM", statistics.hits_per_minute, statistics.tempo)
+        } else {
+            format!("{:.0}/min", statistics.hits_per_minute)
+        };
+        let lines = [
+            format!("Hits: {}", statistics.hit_count),
+            format!("Rate: {}", rate),
+            format!("Attack: {:.1} ms, {:+.1} dB (max {:+.1} dB)",
+                    statistics.attack_length * 1000.0,
+                    statistics.average_attack_gain,
+                    statistics.max_attack_gain),
+            format!("Sustain: {:.0} ms, {:+.1} dB (max {:+.1} dB)",
+                    statistics.sustain_length * 1000.0,
+                    statistics.average_sustain_gain,
+                    statistics.max_sustain_gain),
+        ];
+
+        cr.set_source_rgba(0.8, 0.8, 0.8, self.disable_alpha);
+        cr.set_font_size(10.0);
+        for (i, line) in lines.iter().enumerate() {
+            let extents = cr.text_extents(line);
+            cr.move_to(right - MARGIN - extents.width, top + LINE_HEIGHT * (i + 1) as f64);
+            cr.show_text(line);
+        }
+    }
+}
+
+/// Draws the average of a signal per pixel. The trace is interrupted where
+/// the signal is missing, e.g. where it was switched off.
+fn draw_trace(buckets: &[Bucket],
+              buckets_per_pixel: usize,
+              osci_coord_system: &jilar::osci::OsciCoordSystem,
+              cr: &cairo::Context,
+              value: impl Fn(&Bucket) -> Option<(f32, f32)>) {
+    let mut x = osci_coord_system.left();
+    let mut drawing = false;
+    for chunk in buckets.chunks(buckets_per_pixel) {
+        let (sum, count) = chunk.iter()
+            .filter_map(&value)
+            .fold((0.0f32, 0), |(sum, count), (min, max)| (sum + (min + max) / 2.0, count + 1));
+        if count > 0 {
+            let y = osci_coord_system.scale_y((sum / count as f32) as f64);
+            if drawing {
+                cr.line_to(x, y);
+            } else {
+                cr.move_to(x, y);
+            }
+        }
+        drawing = count > 0;
+
+        x += 1.0;
+        if x > osci_coord_system.right() {
+            break
+        }
+    }
+    cr.stroke();
+}
+
 impl jilar::osci::DrawingTask for OsciDrawings {
     fn draw(&mut self, osci_coord_system: jilar::osci::OsciCoordSystem, cr: &cairo::Context) {
-        let input_signal = self.input_signal.read().unwrap();
-        let output_signal = self.output_signal.read().unwrap();
-        let gain_signal = self.gain_signal.read().unwrap();
+        let signals = self.signals.read().unwrap();
         let state = *self.state.read().unwrap();
 
         if state.enabled {
@@ -731,22 +1242,75 @@ impl jilar::osci::DrawingTask for OsciDrawings {
             }
         }
 
-        let samples_per_pixel =
-            (self.sample_rate * state.display_time / osci_coord_system.width())
-            .ceil() as usize;
+        if *self.osci_width.read().unwrap() != osci_coord_system.width() {
+            *self.osci_width.write().unwrap() = osci_coord_system.width();
+        }
+
+        let buckets_per_pixel =
+            (state.bucket_rate * state.display_time / osci_coord_system.width())
+            .ceil().max(1.0) as usize;
 
         let left = osci_coord_system.left();
         let top = osci_coord_system.top();
         let right = osci_coord_system.right();
         let bottom = osci_coord_system.bottom();
 
+        if state.waiting {
+            let text = "Waiting for the plugin …";
+            cr.set_source_rgb(0.8, 0.8, 0.8);
+            cr.set_font_size(16.0);
+            let extents = cr.text_extents(text);
+            cr.move_to((left + right - extents.width) / 2.0, (top + bottom + extents.height) / 2.0);
+            cr.show_text(text);
+            return;
+        }
+
+        if let Some(plugin_version) = state.version_mismatch {
+            let lines = [
+                "Plugin/UI version mismatch".to_string(),
+                format!("The plugin speaks protocol version {}, the UI version {}.",
+                        plugin_version, PROTOCOL_VERSION),
+                "Please install the plugin and the UI of the same release.".to_string(),
+            ];
+            cr.set_source_rgb(0.6, 0.1, 0.1);
+            cr.rectangle(left, top, right - left, bottom - top);
+            cr.fill();
+            cr.set_source_rgb(1.0, 1.0, 1.0);
+            for (i, line) in lines.iter().enumerate() {
+                cr.set_font_size(if i == 0 { 16.0 } else { 12.0 });
+                let extents = cr.text_extents(line);
+                cr.move_to((left + right - extents.width) / 2.0,
+                           (top + bottom) / 2.0 + 20.0 * (i as f64 - 1.0));
+                cr.show_text(line);
+            }
+            return;
+        }
+
+        let mut notes = Vec::new();
+        if state.inspector && state.capabilities & CAPABILITY_INSPECTOR == 0 {
+            notes.push("The plugin does not support the inspector.".to_string());
+        }
+        if state.learn && state.capabilities & CAPABILITY_LEARN == 0 {
+            notes.push("The plugin cannot learn, the host does not provide a worker.".to_string());
+        }
+        if let Some((attack_smooth, sustain_smooth)) = state.suggestion {
+            notes.push(format!("Suggested smooth: attack {:.1} ms, sustain {:.1} ms – press \"Apply\"",
+                               attack_smooth * 1000.0, sustain_smooth * 1000.0));
+        }
+        cr.set_source_rgb(0.8, 0.8, 0.8);
+        cr.set_font_size(12.0);
+        for (i, note) in notes.iter().rev().enumerate() {
+            cr.move_to(left + 4.0, bottom - 4.0 - 16.0 * i as f64);
+            cr.show_text(note);
+        }
+
         cr.set_source_rgba(0.0, 0.0, 1.0, self.disable_alpha);
         cr.set_line_width(0.5);
         cr.move_to(left, osci_coord_system.scale_y(0.0));
 
         let mut x = left;
-        for chunk in gain_signal.chunks(samples_per_pixel) {
-            let val = (chunk.iter().sum::<f32>()/chunk.len() as f32) as f64;
+        for chunk in signals.buckets.chunks(buckets_per_pixel) {
+            let val = (chunk.iter().map(|b| (b.gain.0 + b.gain.1) / 2.0).sum::<f32>()/chunk.len() as f32) as f64;
             cr.line_to(x, osci_coord_system.scale_y(val));
             x += 1.0;
             if x > right {
@@ -757,19 +1321,24 @@ impl jilar::osci::DrawingTask for OsciDrawings {
         cr.line_to(right, osci_coord_system.scale_y(0.0));
         cr.stroke();
 
+        self.draw_transfer_curves(&state, right, top, cr);
+        if let Some(statistics) = state.statistics.as_ref() {
+            // below the box of the transfer curves
+            self.draw_statistics(statistics, right, top + 76.0, cr);
+        }
+
         cr.set_source_rgba(0.4, 0.4, 0.4, 0.4 * self.disable_alpha);
         cr.set_line_width(0.5);
         cr.set_line_join(cairo::LineJoin::Round);
 
-        let attack_point = match state.attack_point {
-            Some(ap) => ap,
-            None => return
-        };
+        if state.attack_point.is_none() {
+            return;
+        }
 
         cr.move_to(left, bottom);
         let mut x = left;
-        for chunk in input_signal[attack_point..].chunks(samples_per_pixel) {
-            let max = (chunk.iter().fold(-160.0f32, |acc, &v| acc.max(v))) as f64;
+        for chunk in signals.buckets.chunks(buckets_per_pixel) {
+            let max = (chunk.iter().fold(-160.0f32, |acc, b| acc.max(b.input.1))) as f64;
             cr.line_to(x, osci_coord_system.scale_y(max));
 
             x += 1.0;
@@ -785,8 +1354,8 @@ impl jilar::osci::DrawingTask for OsciDrawings {
         cr.set_line_join(cairo::LineJoin::Round);
 
         let mut x = left;
-        for chunk in output_signal[attack_point..].chunks(samples_per_pixel) {
-            let max = (chunk.iter().fold(-160.0f32, |acc, &v| acc.max(v))) as f64;
+        for chunk in signals.buckets.chunks(buckets_per_pixel) {
+            let max = (chunk.iter().fold(-160.0f32, |acc, b| acc.max(b.output.1))) as f64;
             cr.line_to(x, osci_coord_system.scale_y(max));
 
             x += 1.0;
@@ -795,10 +1364,43 @@ impl jilar::osci::DrawingTask for OsciDrawings {
             }
         }
         cr.stroke();
+
+        if signals.buckets.iter().any(|b| b.envelopes.is_some()) {
+            cr.set_line_width(0.5);
+            let traces: [(&str, (f64, f64, f64), fn(&Envelopes) -> (f32, f32)); 6] = [
+                ("attack fast", (1.0, 0.4, 0.4), |e| e.attack_fast),
+                ("attack slow", (0.7, 0.1, 0.1), |e| e.attack_slow),
+                ("release fast", (0.4, 1.0, 0.4), |e| e.release_fast),
+                ("release slow", (0.1, 0.6, 0.1), |e| e.release_slow),
+                ("beat level", (1.0, 0.3, 1.0), |e| e.beat_level),
+                ("pregain", (0.0, 1.0, 1.0), |e| e.pregain),
+            ];
+            for (i, &(name, (r, g, b), envelope)) in traces.iter().enumerate() {
+                cr.set_source_rgba(r, g, b, 0.8 * self.disable_alpha);
+                draw_trace(&signals.buckets, buckets_per_pixel, &osci_coord_system, cr,
+                           |bucket| bucket.envelopes.as_ref().map(envelope));
+                cr.move_to(left + 4.0, top + 12.0 * (i + 1) as f64);
+                cr.show_text(name);
+            }
+        }
+
+        if signals.buckets.iter().any(|b| b.threshold.is_some()) {
+            cr.set_source_rgba(1.0, 0.8, 0.0, 0.8 * self.disable_alpha);
+            cr.set_line_width(0.5);
+            cr.set_dash(&[2.0, 2.0], 0.0);
+            draw_trace(&signals.buckets, buckets_per_pixel, &osci_coord_system, cr, |bucket| bucket.threshold);
+            cr.set_dash(&[], 0.0);
+        }
+
         cr.reset_clip();
 
-        if let Some(release_point) = state.release_point {
-            let x = left + (release_point-attack_point) as f64/samples_per_pixel as f64;
+        let position = |frame: Option<u64>| {
+            frame
+                .and_then(|frame| signals.position(frame, state.bucket_size))
+                .map(|pos| left + pos / buckets_per_pixel as f64)
+        };
+
+        if let Some(x) = position(state.release_point) {
             cr.set_source_rgba(1.0, 0.0, 0.0, self.disable_alpha);
             cr.set_line_width(1.0);
             cr.move_to(x, top);
@@ -806,8 +1408,7 @@ impl jilar::osci::DrawingTask for OsciDrawings {
             cr.stroke();
         }
 
-        if let Some(idle_point) = state.idle_point {
-            let x = left + (idle_point-attack_point) as f64/samples_per_pixel as f64;
+        if let Some(x) = position(state.idle_point) {
             cr.set_source_rgba(0.0, 1.0, 0.0, self.disable_alpha);
             cr.set_line_width(0.5);
             cr.move_to(x, top);
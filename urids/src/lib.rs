@@ -1,20 +1,53 @@
 use lv2::prelude::*;
 
+pub mod protocol;
+pub mod shape;
+
 #[uri("http://johannes-mueller.org/lv2/envolvigo#PluginConfig")]
 pub struct PluginConfig;
 
+#[uri("http://johannes-mueller.org/lv2/envolvigo#protocol_version")]
+pub struct ProtocolVersion;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#capabilities")]
+pub struct Capabilities;
+
 #[uri("http://johannes-mueller.org/lv2/envolvigo#ui_on")]
 pub struct UIOn;
 
 #[uri("http://johannes-mueller.org/lv2/envolvigo#ui_off")]
 pub struct UIOff;
 
+#[uri("http://johannes-mueller.org/lv2/envolvigo#RequestConfig")]
+pub struct RequestConfig;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#UpdateRate")]
+pub struct UpdateRate;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#updates_per_second")]
+pub struct UpdatesPerSecond;
+
 #[uri("http://johannes-mueller.org/lv2/envolvigo#PluginState")]
 pub struct PluginState;
 
 #[uri("http://johannes-mueller.org/lv2/envolvigo#AudioData")]
 pub struct AudioData;
 
+#[uri("http://johannes-mueller.org/lv2/envolvigo#AudioDataResolution")]
+pub struct AudioDataResolution;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#buckets_per_second")]
+pub struct BucketsPerSecond;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#first_frame")]
+pub struct FirstFrame;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#NotifyOverflow")]
+pub struct NotifyOverflow;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#dropped_messages")]
+pub struct DroppedMessages;
+
 #[uri("http://johannes-mueller.org/lv2/envolvigo#AttackPoint")]
 pub struct AttackPoint;
 
@@ -33,22 +66,138 @@ pub struct InputSignal;
 #[uri("http://johannes-mueller.org/lv2/envolvigo#output_signal")]
 pub struct OutputSignal;
 
+#[uri("http://johannes-mueller.org/lv2/envolvigo#sidechain_signal")]
+pub struct SidechainSignal;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#threshold_signal")]
+pub struct ThresholdSignal;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#input_left_signal")]
+pub struct InputLeftSignal;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#input_right_signal")]
+pub struct InputRightSignal;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#output_left_signal")]
+pub struct OutputLeftSignal;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#output_right_signal")]
+pub struct OutputRightSignal;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#attack_fast_signal")]
+pub struct AttackFastSignal;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#attack_slow_signal")]
+pub struct AttackSlowSignal;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#release_fast_signal")]
+pub struct ReleaseFastSignal;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#release_slow_signal")]
+pub struct ReleaseSlowSignal;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#beat_level_signal")]
+pub struct BeatLevelSignal;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#pregain_signal")]
+pub struct PregainSignal;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#LearnResult")]
+pub struct LearnResult;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#learned_attack_smooth")]
+pub struct LearnedAttackSmooth;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#learned_sustain_smooth")]
+pub struct LearnedSustainSmooth;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#Statistics")]
+pub struct HitStatistics;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#ResetStatistics")]
+pub struct ResetStatistics;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#hit_count")]
+pub struct HitCount;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#hits_per_minute")]
+pub struct HitsPerMinute;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#tempo")]
+pub struct Tempo;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#attack_length")]
+pub struct AttackLength;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#sustain_length")]
+pub struct SustainLength;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#max_attack_gain")]
+pub struct MaxAttackGain;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#average_attack_gain")]
+pub struct AverageAttackGain;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#max_sustain_gain")]
+pub struct MaxSustainGain;
+
+#[uri("http://johannes-mueller.org/lv2/envolvigo#average_sustain_gain")]
+pub struct AverageSustainGain;
+
 #[derive(URIDCollection)]
 pub struct URIDs {
     pub atom: AtomURIDCollection,
     pub unit: UnitURIDCollection,
     pub buf_size: BufSizeURIDCollection,
+    pub midi: MidiURIDCollection,
     pub parameters: ParametersURIDCollection,
+    pub time: TimeURIDCollection,
     pub ui: UIURIDCollection,
     pub plugin_config: URID<PluginConfig>,
+    pub protocol_version: URID<ProtocolVersion>,
+    pub capabilities: URID<Capabilities>,
     pub ui_on: URID<UIOn>,
     pub ui_off: URID<UIOff>,
+    pub request_config: URID<RequestConfig>,
+    pub update_rate: URID<UpdateRate>,
+    pub updates_per_second: URID<UpdatesPerSecond>,
     pub plugin_state: URID<PluginState>,
+    pub learn_result: URID<LearnResult>,
+    pub learned_attack_smooth: URID<LearnedAttackSmooth>,
+    pub learned_sustain_smooth: URID<LearnedSustainSmooth>,
+    pub hit_statistics: URID<HitStatistics>,
+    pub reset_statistics: URID<ResetStatistics>,
+    pub hit_count: URID<HitCount>,
+    pub hits_per_minute: URID<HitsPerMinute>,
+    pub tempo: URID<Tempo>,
+    pub attack_length: URID<AttackLength>,
+    pub sustain_length: URID<SustainLength>,
+    pub max_attack_gain: URID<MaxAttackGain>,
+    pub average_attack_gain: URID<AverageAttackGain>,
+    pub max_sustain_gain: URID<MaxSustainGain>,
+    pub average_sustain_gain: URID<AverageSustainGain>,
+    pub notify_overflow: URID<NotifyOverflow>,
+    pub dropped_messages: URID<DroppedMessages>,
     pub attack_point: URID<AttackPoint>,
     pub release_point: URID<ReleasePoint>,
     pub idle_point: URID<IdlePoint>,
     pub audio_data: URID<AudioData>,
+    pub audio_data_resolution: URID<AudioDataResolution>,
+    pub buckets_per_second: URID<BucketsPerSecond>,
+    pub first_frame: URID<FirstFrame>,
     pub input_signal: URID<InputSignal>,
     pub output_signal: URID<OutputSignal>,
-    pub gain_signal: URID<GainSignal>
+    pub sidechain_signal: URID<SidechainSignal>,
+    pub threshold_signal: URID<ThresholdSignal>,
+    pub gain_signal: URID<GainSignal>,
+    pub input_left_signal: URID<InputLeftSignal>,
+    pub input_right_signal: URID<InputRightSignal>,
+    pub output_left_signal: URID<OutputLeftSignal>,
+    pub output_right_signal: URID<OutputRightSignal>,
+    pub attack_fast_signal: URID<AttackFastSignal>,
+    pub attack_slow_signal: URID<AttackSlowSignal>,
+    pub release_fast_signal: URID<ReleaseFastSignal>,
+    pub release_slow_signal: URID<ReleaseSlowSignal>,
+    pub beat_level_signal: URID<BeatLevelSignal>,
+    pub pregain_signal: URID<PregainSignal>
 }
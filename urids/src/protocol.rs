@@ -0,0 +1,1073 @@
+//! The messages exchanged between the plugin and the UI
+//!
+//! Both sides encode and decode the atom objects only by the functions of
+//! this module, so the plugin and the UI always agree on the format.
+
+use lv2::prelude::*;
+use lv2_atom::object::{ObjectReader, ObjectWriter};
+use lv2_atom::sequence::SequenceWriter;
+use lv2_atom::vector::VectorWriter;
+
+use crate::URIDs;
+
+/// The version of the messages. It is increased whenever a message changes in
+/// a way that the other side would misinterpret it. The plugin and the UI are
+/// installed separately, so they can be of different versions.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The plugin can suggest smooth settings, see `PluginMessage::LearnResult`.
+pub const CAPABILITY_LEARN: u32 = 1;
+/// The plugin can send its internal envelopes, see `InspectorSignals`.
+pub const CAPABILITY_INSPECTOR: u32 = 1 << 1;
+/// The plugin collects statistics of the hits, see `Statistics`.
+pub const CAPABILITY_STATISTICS: u32 = 1 << 2;
+
+/// The capabilities of this version
+pub const CAPABILITIES: u32 = CAPABILITY_LEARN | CAPABILITY_INSPECTOR | CAPABILITY_STATISTICS;
+
+/// Messages sent from the UI to the plugin by the control port
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UIMessage {
+    /// The UI has been opened and wants to receive the plugin state.
+    On,
+    /// The UI has been closed, no need to send anything anymore.
+    Off,
+    /// The UI has not received the plugin config (yet) and asks for it.
+    /// The plugin only answers with the config, it does not switch the
+    /// notifications on.
+    RequestConfig,
+    /// The number of buckets per second the UI wants the audio data in
+    Resolution { buckets_per_second: f32 },
+    /// Starts the statistics of the hits over.
+    ResetStatistics,
+    /// How often per second the UI is updated. The plugin does not notify
+    /// the UI more often than that. Until it is sent, the plugin notifies
+    /// the UI in every processed block.
+    UpdateRate { updates_per_second: f32 },
+}
+
+impl UIMessage {
+    /// The header of the object atom the message is encoded in
+    pub fn header(&self, urids: &URIDs) -> ObjectHeader {
+        let otype = match self {
+            UIMessage::On => urids.ui_on.into_general(),
+            UIMessage::Off => urids.ui_off.into_general(),
+            UIMessage::RequestConfig => urids.request_config.into_general(),
+            UIMessage::Resolution { .. } => urids.audio_data_resolution.into_general(),
+            UIMessage::ResetStatistics => urids.reset_statistics.into_general(),
+            UIMessage::UpdateRate { .. } => urids.update_rate.into_general(),
+        };
+        ObjectHeader { id: None, otype }
+    }
+
+    /// Writes the properties of the message into an object initialized with
+    /// `header()`.
+    pub fn write_properties(&self, urids: &URIDs, object_writer: &mut ObjectWriter) -> Option<()> {
+        match self {
+            UIMessage::Resolution { buckets_per_second } => {
+                object_writer.init(urids.buckets_per_second, urids.atom.float, *buckets_per_second)?;
+            }
+            UIMessage::UpdateRate { updates_per_second } => {
+                object_writer.init(urids.updates_per_second, urids.atom.float, *updates_per_second)?;
+            }
+            _ => {}
+        }
+        Some(())
+    }
+
+    /// Returns `None` if the object is not a UI message or if a property is
+    /// missing.
+    pub fn read(urids: &URIDs, header: &ObjectHeader, object_reader: ObjectReader) -> Option<Self> {
+        if header.otype == urids.ui_on {
+            Some(UIMessage::On)
+        } else if header.otype == urids.ui_off {
+            Some(UIMessage::Off)
+        } else if header.otype == urids.request_config {
+            Some(UIMessage::RequestConfig)
+        } else if header.otype == urids.audio_data_resolution {
+            let mut buckets_per_second = None;
+            for (property, atom) in object_reader {
+                if property.key == urids.buckets_per_second {
+                    buckets_per_second = Some(atom.read(urids.atom.float, ())?);
+                }
+            }
+            Some(UIMessage::Resolution { buckets_per_second: buckets_per_second? })
+        } else if header.otype == urids.reset_statistics {
+            Some(UIMessage::ResetStatistics)
+        } else if header.otype == urids.update_rate {
+            let mut updates_per_second = None;
+            for (property, atom) in object_reader {
+                if property.key == urids.updates_per_second {
+                    updates_per_second = Some(atom.read(urids.atom.float, ())?);
+                }
+            }
+            Some(UIMessage::UpdateRate { updates_per_second: updates_per_second? })
+        } else {
+            None
+        }
+    }
+}
+
+/// A transition of the shaper. The plugin sets one of the points per message.
+/// The points are absolute frames, counted since the plugin has been
+/// instantiated, so they stay valid if messages are lost.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct State {
+    pub attack_point: Option<u64>,
+    pub release_point: Option<u64>,
+    pub idle_point: Option<u64>,
+}
+
+/// The statistics of the hits since the plugin has been instantiated or they
+/// have been reset. The lengths are averages in seconds, the gains are in dB.
+/// The maximum gains are the ones furthest from 0 dB, as the sustain is rather
+/// cut than boosted.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Statistics {
+    pub hit_count: u32,
+    /// Zero before the second hit
+    pub hits_per_minute: f32,
+    /// The tempo in BPM the intervals between the hits suggest, zero before
+    /// there are enough hits to tell
+    pub tempo: f32,
+    pub attack_length: f32,
+    pub sustain_length: f32,
+    pub max_attack_gain: f32,
+    pub average_attack_gain: f32,
+    pub max_sustain_gain: f32,
+    pub average_sustain_gain: f32,
+}
+
+/// The signals of the buckets completed since the last notification, which
+/// may span several processed blocks (see `UIMessage::UpdateRate`). Each
+/// signal holds the minimum and the maximum in dB of every bucket, see
+/// `buckets()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AudioData<'a> {
+    pub buckets_per_second: f32,
+    /// The absolute frame the first bucket starts at
+    pub first_frame: u64,
+
+    pub gain_signal: &'a [f32],
+    /// See `output_signal`
+    pub input_signal: &'a [f32],
+    /// Only sent if the sidechain is in use
+    pub sidechain_signal: Option<&'a [f32]>,
+    /// Only sent if the adaptive threshold is enabled
+    pub threshold_signal: Option<&'a [f32]>,
+    /// The signals of all the channels combined as chosen by the channel
+    /// view of the plugin. The maximum if not a stereo plugin.
+    pub output_signal: &'a [f32],
+    /// Only sent by the stereo plugin
+    pub stereo: Option<StereoSignals<'a>>,
+    /// Only sent in inspector mode
+    pub inspector: Option<InspectorSignals<'a>>,
+}
+
+/// The input and the output of both channels, in the same format as the other
+/// signals of `AudioData`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StereoSignals<'a> {
+    pub input_left: &'a [f32],
+    pub input_right: &'a [f32],
+    pub output_left: &'a [f32],
+    pub output_right: &'a [f32],
+}
+
+impl<'a> StereoSignals<'a> {
+    const LEN: usize = 4;
+
+    fn signals(&self) -> [&'a [f32]; Self::LEN] {
+        [self.input_left, self.input_right, self.output_left, self.output_right]
+    }
+
+    fn truncated(&self, end: usize) -> Self {
+        StereoSignals {
+            input_left: &self.input_left[..end],
+            input_right: &self.input_right[..end],
+            output_left: &self.output_left[..end],
+            output_right: &self.output_right[..end],
+        }
+    }
+}
+
+/// The internal envelopes of the shaper, in the same format as the other
+/// signals of `AudioData`. The pregain is the gain before the boost and the
+/// shape are applied.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InspectorSignals<'a> {
+    pub attack_fast: &'a [f32],
+    pub attack_slow: &'a [f32],
+    pub release_fast: &'a [f32],
+    pub release_slow: &'a [f32],
+    pub beat_level: &'a [f32],
+    pub pregain: &'a [f32],
+}
+
+impl<'a> InspectorSignals<'a> {
+    const LEN: usize = 6;
+
+    fn signals(&self) -> [&'a [f32]; Self::LEN] {
+        [self.attack_fast, self.attack_slow, self.release_fast,
+         self.release_slow, self.beat_level, self.pregain]
+    }
+
+    fn truncated(&self, end: usize) -> Self {
+        InspectorSignals {
+            attack_fast: &self.attack_fast[..end],
+            attack_slow: &self.attack_slow[..end],
+            release_fast: &self.release_fast[..end],
+            release_slow: &self.release_slow[..end],
+            beat_level: &self.beat_level[..end],
+            pregain: &self.pregain[..end],
+        }
+    }
+}
+
+impl<'a> AudioData<'a> {
+    /// The number of buckets in the message
+    pub fn len(&self) -> usize {
+        self.input_signal.len() / 2
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.input_signal.is_empty()
+    }
+
+    /// The first `n_buckets` buckets of the message
+    pub fn truncated(&self, n_buckets: usize) -> Self {
+        let end = 2 * n_buckets.min(self.len());
+        AudioData {
+            gain_signal: &self.gain_signal[..end],
+            input_signal: &self.input_signal[..end],
+            sidechain_signal: self.sidechain_signal.map(|signal| &signal[..end]),
+            threshold_signal: self.threshold_signal.map(|signal| &signal[..end]),
+            output_signal: &self.output_signal[..end],
+            stereo: self.stereo.map(|stereo| stereo.truncated(end)),
+            inspector: self.inspector.map(|inspector| inspector.truncated(end)),
+            ..*self
+        }
+    }
+
+    /// The size of a message with all the signals and `n_buckets` buckets as
+    /// event in a sequence. The stereo and the inspector signals are only
+    /// counted if `stereo` and `inspector` are set.
+    pub fn max_size(n_buckets: usize, stereo: bool, inspector: bool) -> usize {
+        let mut n_signals = 5;
+        if stereo {
+            n_signals += StereoSignals::LEN;
+        }
+        if inspector {
+            n_signals += InspectorSignals::LEN;
+        }
+        EVENT_HEADER_SIZE + OBJECT_HEADER_SIZE
+            + 2 * scalar_property_size()
+            + n_signals * vector_property_size(2 * n_buckets)
+    }
+
+    /// The number of buckets, that fit into `size` bytes for sure
+    pub fn max_buckets(size: usize, stereo: bool, inspector: bool) -> usize {
+        let bucket_size = Self::max_size(1, stereo, inspector) - Self::max_size(0, stereo, inspector);
+        size.saturating_sub(Self::max_size(0, stereo, inspector)) / bucket_size
+    }
+}
+
+// The sizes of the parts of an atom in a sequence. The bodies of all atoms are
+// padded to 64 bits.
+const EVENT_HEADER_SIZE: usize = 8 + 8;
+const OBJECT_HEADER_SIZE: usize = 8;
+const PROPERTY_HEADER_SIZE: usize = 16;
+
+fn padded(size: usize) -> usize {
+    (size + 7) & !7
+}
+
+fn scalar_property_size() -> usize {
+    PROPERTY_HEADER_SIZE + 8
+}
+
+fn vector_property_size(n_elements: usize) -> usize {
+    PROPERTY_HEADER_SIZE + padded(8 + 4 * n_elements)
+}
+
+/// Iterates over the `(min, max)` pairs of a signal of `AudioData`.
+pub fn buckets(signal: &[f32]) -> impl Iterator<Item = (f32, f32)> + '_ {
+    signal.chunks_exact(2).map(|bucket| (bucket[0], bucket[1]))
+}
+
+/// Messages sent from the plugin to the UI by the notify port
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PluginMessage<'a> {
+    /// Sent after the UI has been switched on or has asked for it, and then
+    /// repeated regularly in case it got lost. Plugins from before the
+    /// versioning are reported as version 0 without any capabilities.
+    Config { sample_rate: f32, protocol_version: u32, capabilities: u32 },
+    /// Smooth settings suggested by the learn mode
+    LearnResult { attack_smooth: f32, sustain_smooth: f32 },
+    /// Sent for every transition of the shaper, possibly a few of them in
+    /// one notification
+    State(State),
+    /// Sent regularly while the UI is on and after a reset
+    Statistics(Statistics),
+    /// Sent once per notification period with the buckets completed since
+    /// the last one, if there are any
+    AudioData(AudioData<'a>),
+    /// The notify port was too small for the messages of the last blocks.
+    Overflow { dropped_messages: u32 },
+}
+
+impl<'a> PluginMessage<'a> {
+    /// The header of the object atom the message is encoded in
+    pub fn header(&self, urids: &URIDs) -> ObjectHeader {
+        let otype = match self {
+            PluginMessage::Config { .. } => urids.plugin_config.into_general(),
+            PluginMessage::LearnResult { .. } => urids.learn_result.into_general(),
+            PluginMessage::State(_) => urids.plugin_state.into_general(),
+            PluginMessage::Statistics(_) => urids.hit_statistics.into_general(),
+            PluginMessage::AudioData(_) => urids.audio_data.into_general(),
+            PluginMessage::Overflow { .. } => urids.notify_overflow.into_general(),
+        };
+        ObjectHeader { id: None, otype }
+    }
+
+    /// The number of bytes the message takes as event in a sequence
+    pub fn size(&self) -> usize {
+        let properties = match self {
+            PluginMessage::Config { .. } => 3 * scalar_property_size(),
+            PluginMessage::LearnResult { .. } => 2 * scalar_property_size(),
+            PluginMessage::State(state) => {
+                [state.attack_point, state.release_point, state.idle_point]
+                    .iter()
+                    .filter(|point| point.is_some())
+                    .count() * scalar_property_size()
+            }
+            PluginMessage::Statistics(_) => 9 * scalar_property_size(),
+            PluginMessage::AudioData(audio_data) => {
+                let optional_signals = [audio_data.sidechain_signal, audio_data.threshold_signal];
+                let stereo_signals = audio_data.stereo.map(|stereo| stereo.signals());
+                let inspector_signals = audio_data.inspector.map(|inspector| inspector.signals());
+                2 * scalar_property_size()
+                    + 3 * vector_property_size(audio_data.input_signal.len())
+                    + optional_signals
+                    .iter()
+                    .flatten()
+                    .chain(stereo_signals.iter().flatten())
+                    .chain(inspector_signals.iter().flatten())
+                    .map(|signal| vector_property_size(signal.len()))
+                    .sum::<usize>()
+            }
+            PluginMessage::Overflow { .. } => scalar_property_size(),
+        };
+        EVENT_HEADER_SIZE + OBJECT_HEADER_SIZE + properties
+    }
+
+    /// Appends the message as object to a sequence. Returns `None` if the
+    /// sequence has not enough space left.
+    pub fn write(&self, urids: &URIDs, sequence_writer: &mut SequenceWriter) -> Option<()> {
+        let mut object_writer = sequence_writer.init(
+            TimeStamp::Frames(0),
+            urids.atom.object,
+            self.header(urids)
+        )?;
+        self.write_properties(urids, &mut object_writer)
+    }
+
+    /// Writes the properties of the message into an object initialized with
+    /// `header()`.
+    pub fn write_properties(&self, urids: &URIDs, object_writer: &mut ObjectWriter) -> Option<()> {
+        match self {
+            PluginMessage::Config { sample_rate, protocol_version, capabilities } => {
+                object_writer.init(urids.parameters.sample_rate, urids.atom.float, *sample_rate)?;
+                object_writer.init(urids.protocol_version, urids.atom.int, *protocol_version as i32)?;
+                object_writer.init(urids.capabilities, urids.atom.int, *capabilities as i32)?;
+            }
+            PluginMessage::LearnResult { attack_smooth, sustain_smooth } => {
+                object_writer.init(urids.learned_attack_smooth, urids.atom.float, *attack_smooth)?;
+                object_writer.init(urids.learned_sustain_smooth, urids.atom.float, *sustain_smooth)?;
+            }
+            PluginMessage::State(state) => {
+                if let Some(point) = state.attack_point {
+                    object_writer.init(urids.attack_point, urids.atom.long, point as i64)?;
+                }
+                if let Some(point) = state.release_point {
+                    object_writer.init(urids.release_point, urids.atom.long, point as i64)?;
+                }
+                if let Some(point) = state.idle_point {
+                    object_writer.init(urids.idle_point, urids.atom.long, point as i64)?;
+                }
+            }
+            PluginMessage::Statistics(statistics) => {
+                object_writer.init(urids.hit_count, urids.atom.int, statistics.hit_count as i32)?;
+                object_writer.init(urids.hits_per_minute, urids.atom.float, statistics.hits_per_minute)?;
+                object_writer.init(urids.tempo, urids.atom.float, statistics.tempo)?;
+                object_writer.init(urids.attack_length, urids.atom.float, statistics.attack_length)?;
+                object_writer.init(urids.sustain_length, urids.atom.float, statistics.sustain_length)?;
+                object_writer.init(urids.max_attack_gain, urids.atom.float, statistics.max_attack_gain)?;
+                object_writer.init(urids.average_attack_gain, urids.atom.float, statistics.average_attack_gain)?;
+                object_writer.init(urids.max_sustain_gain, urids.atom.float, statistics.max_sustain_gain)?;
+                object_writer.init(urids.average_sustain_gain, urids.atom.float, statistics.average_sustain_gain)?;
+            }
+            PluginMessage::AudioData(audio_data) => {
+                object_writer.init(urids.buckets_per_second, urids.atom.float, audio_data.buckets_per_second)?;
+                object_writer.init(urids.first_frame, urids.atom.long, audio_data.first_frame as i64)?;
+                write_signal(urids, object_writer, urids.gain_signal, audio_data.gain_signal)?;
+                write_signal(urids, object_writer, urids.input_signal, audio_data.input_signal)?;
+                if let Some(signal) = audio_data.sidechain_signal {
+                    write_signal(urids, object_writer, urids.sidechain_signal, signal)?;
+                }
+                if let Some(signal) = audio_data.threshold_signal {
+                    write_signal(urids, object_writer, urids.threshold_signal, signal)?;
+                }
+                write_signal(urids, object_writer, urids.output_signal, audio_data.output_signal)?;
+                if let Some(stereo) = audio_data.stereo {
+                    write_signal(urids, object_writer, urids.input_left_signal, stereo.input_left)?;
+                    write_signal(urids, object_writer, urids.input_right_signal, stereo.input_right)?;
+                    write_signal(urids, object_writer, urids.output_left_signal, stereo.output_left)?;
+                    write_signal(urids, object_writer, urids.output_right_signal, stereo.output_right)?;
+                }
+                if let Some(inspector) = audio_data.inspector {
+                    write_signal(urids, object_writer, urids.attack_fast_signal, inspector.attack_fast)?;
+                    write_signal(urids, object_writer, urids.attack_slow_signal, inspector.attack_slow)?;
+                    write_signal(urids, object_writer, urids.release_fast_signal, inspector.release_fast)?;
+                    write_signal(urids, object_writer, urids.release_slow_signal, inspector.release_slow)?;
+                    write_signal(urids, object_writer, urids.beat_level_signal, inspector.beat_level)?;
+                    write_signal(urids, object_writer, urids.pregain_signal, inspector.pregain)?;
+                }
+            }
+            PluginMessage::Overflow { dropped_messages } => {
+                object_writer.init(urids.dropped_messages, urids.atom.int, *dropped_messages as i32)?;
+            }
+        }
+        Some(())
+    }
+
+    /// Decodes an object read from the notify port. Returns `None` if the
+    /// object is not a plugin message or if a property is missing or of the
+    /// wrong type. Unknown properties are ignored. The stereo and the
+    /// inspector signals must be sent either all or none.
+    pub fn read(urids: &URIDs, header: &ObjectHeader, object_reader: ObjectReader<'a>) -> Option<Self> {
+        if header.otype == urids.plugin_config {
+            let mut sample_rate = None;
+            let mut protocol_version = 0;
+            let mut capabilities = 0;
+            for (property, atom) in object_reader {
+                if property.key == urids.parameters.sample_rate {
+                    sample_rate = Some(atom.read(urids.atom.float, ())?);
+                } else if property.key == urids.protocol_version {
+                    protocol_version = atom.read(urids.atom.int, ())? as u32;
+                } else if property.key == urids.capabilities {
+                    capabilities = atom.read(urids.atom.int, ())? as u32;
+                }
+            }
+            Some(PluginMessage::Config {
+                sample_rate: sample_rate?,
+                protocol_version,
+                capabilities,
+            })
+        } else if header.otype == urids.learn_result {
+            let mut attack_smooth = None;
+            let mut sustain_smooth = None;
+            for (property, atom) in object_reader {
+                if property.key == urids.learned_attack_smooth {
+                    attack_smooth = Some(atom.read(urids.atom.float, ())?);
+                } else if property.key == urids.learned_sustain_smooth {
+                    sustain_smooth = Some(atom.read(urids.atom.float, ())?);
+                }
+            }
+            Some(PluginMessage::LearnResult {
+                attack_smooth: attack_smooth?,
+                sustain_smooth: sustain_smooth?,
+            })
+        } else if header.otype == urids.plugin_state {
+            let mut state = State {
+                attack_point: None,
+                release_point: None,
+                idle_point: None,
+            };
+            for (property, atom) in object_reader {
+                if property.key == urids.attack_point {
+                    state.attack_point = Some(atom.read(urids.atom.long, ())? as u64);
+                } else if property.key == urids.release_point {
+                    state.release_point = Some(atom.read(urids.atom.long, ())? as u64);
+                } else if property.key == urids.idle_point {
+                    state.idle_point = Some(atom.read(urids.atom.long, ())? as u64);
+                }
+            }
+            Some(PluginMessage::State(state))
+        } else if header.otype == urids.hit_statistics {
+            let mut hit_count = None;
+            let mut hits_per_minute = None;
+            let mut tempo = None;
+            let mut attack_length = None;
+            let mut sustain_length = None;
+            let mut max_attack_gain = None;
+            let mut average_attack_gain = None;
+            let mut max_sustain_gain = None;
+            let mut average_sustain_gain = None;
+            for (property, atom) in object_reader {
+                if property.key == urids.hit_count {
+                    hit_count = Some(atom.read(urids.atom.int, ())? as u32);
+                } else if property.key == urids.hits_per_minute {
+                    hits_per_minute = Some(atom.read(urids.atom.float, ())?);
+                } else if property.key == urids.tempo {
+                    tempo = Some(atom.read(urids.atom.float, ())?);
+                } else if property.key == urids.attack_length {
+                    attack_length = Some(atom.read(urids.atom.float, ())?);
+                } else if property.key == urids.sustain_length {
+                    sustain_length = Some(atom.read(urids.atom.float, ())?);
+                } else if property.key == urids.max_attack_gain {
+                    max_attack_gain = Some(atom.read(urids.atom.float, ())?);
+                } else if property.key == urids.average_attack_gain {
+                    average_attack_gain = Some(atom.read(urids.atom.float, ())?);
+                } else if property.key == urids.max_sustain_gain {
+                    max_sustain_gain = Some(atom.read(urids.atom.float, ())?);
+                } else if property.key == urids.average_sustain_gain {
+                    average_sustain_gain = Some(atom.read(urids.atom.float, ())?);
+                }
+            }
+            Some(PluginMessage::Statistics(Statistics {
+                hit_count: hit_count?,
+                hits_per_minute: hits_per_minute?,
+                tempo: tempo?,
+                attack_length: attack_length?,
+                sustain_length: sustain_length?,
+                max_attack_gain: max_attack_gain?,
+                average_attack_gain: average_attack_gain?,
+                max_sustain_gain: max_sustain_gain?,
+                average_sustain_gain: average_sustain_gain?,
+            }))
+        } else if header.otype == urids.audio_data {
+            let mut buckets_per_second = None;
+            let mut first_frame = None;
+            let mut gain_signal = None;
+            let mut input_signal = None;
+            let mut sidechain_signal = None;
+            let mut threshold_signal = None;
+            let mut output_signal = None;
+            let mut input_left = None;
+            let mut input_right = None;
+            let mut output_left = None;
+            let mut output_right = None;
+            let mut attack_fast = None;
+            let mut attack_slow = None;
+            let mut release_fast = None;
+            let mut release_slow = None;
+            let mut beat_level = None;
+            let mut pregain = None;
+            for (property, atom) in object_reader {
+                if property.key == urids.buckets_per_second {
+                    buckets_per_second = Some(atom.read(urids.atom.float, ())?);
+                } else if property.key == urids.first_frame {
+                    first_frame = Some(atom.read(urids.atom.long, ())? as u64);
+                } else if property.key == urids.gain_signal {
+                    gain_signal = Some(atom.read(urids.atom.vector(), urids.atom.float)?);
+                } else if property.key == urids.input_signal {
+                    input_signal = Some(atom.read(urids.atom.vector(), urids.atom.float)?);
+                } else if property.key == urids.sidechain_signal {
+                    sidechain_signal = Some(atom.read(urids.atom.vector(), urids.atom.float)?);
+                } else if property.key == urids.threshold_signal {
+                    threshold_signal = Some(atom.read(urids.atom.vector(), urids.atom.float)?);
+                } else if property.key == urids.output_signal {
+                    output_signal = Some(atom.read(urids.atom.vector(), urids.atom.float)?);
+                } else if property.key == urids.input_left_signal {
+                    input_left = Some(atom.read(urids.atom.vector(), urids.atom.float)?);
+                } else if property.key == urids.input_right_signal {
+                    input_right = Some(atom.read(urids.atom.vector(), urids.atom.float)?);
+                } else if property.key == urids.output_left_signal {
+                    output_left = Some(atom.read(urids.atom.vector(), urids.atom.float)?);
+                } else if property.key == urids.output_right_signal {
+                    output_right = Some(atom.read(urids.atom.vector(), urids.atom.float)?);
+                } else if property.key == urids.attack_fast_signal {
+                    attack_fast = Some(atom.read(urids.atom.vector(), urids.atom.float)?);
+                } else if property.key == urids.attack_slow_signal {
+                    attack_slow = Some(atom.read(urids.atom.vector(), urids.atom.float)?);
+                } else if property.key == urids.release_fast_signal {
+                    release_fast = Some(atom.read(urids.atom.vector(), urids.atom.float)?);
+                } else if property.key == urids.release_slow_signal {
+                    release_slow = Some(atom.read(urids.atom.vector(), urids.atom.float)?);
+                } else if property.key == urids.beat_level_signal {
+                    beat_level = Some(atom.read(urids.atom.vector(), urids.atom.float)?);
+                } else if property.key == urids.pregain_signal {
+                    pregain = Some(atom.read(urids.atom.vector(), urids.atom.float)?);
+                }
+            }
+            let stereo = match (input_left, input_right, output_left, output_right) {
+                (Some(input_left), Some(input_right), Some(output_left), Some(output_right)) => {
+                    Some(StereoSignals {
+                        input_left,
+                        input_right,
+                        output_left,
+                        output_right,
+                    })
+                }
+                (None, None, None, None) => None,
+                _ => return None,
+            };
+            let inspector = match (attack_fast, attack_slow, release_fast, release_slow, beat_level, pregain) {
+                (Some(attack_fast), Some(attack_slow), Some(release_fast),
+                 Some(release_slow), Some(beat_level), Some(pregain)) => Some(InspectorSignals {
+                    attack_fast,
+                    attack_slow,
+                    release_fast,
+                    release_slow,
+                    beat_level,
+                    pregain,
+                }),
+                (None, None, None, None, None, None) => None,
+                _ => return None,
+            };
+            Some(PluginMessage::AudioData(AudioData {
+                buckets_per_second: buckets_per_second?,
+                first_frame: first_frame?,
+                gain_signal: gain_signal?,
+                input_signal: input_signal?,
+                sidechain_signal,
+                threshold_signal,
+                output_signal: output_signal?,
+                stereo,
+                inspector,
+            }))
+        } else if header.otype == urids.notify_overflow {
+            let mut dropped_messages = None;
+            for (property, atom) in object_reader {
+                if property.key == urids.dropped_messages {
+                    dropped_messages = Some(atom.read(urids.atom.int, ())? as u32);
+                }
+            }
+            Some(PluginMessage::Overflow { dropped_messages: dropped_messages? })
+        } else {
+            None
+        }
+    }
+}
+
+fn write_signal<K: ?Sized>(urids: &URIDs,
+                           object_writer: &mut ObjectWriter,
+                           key: URID<K>,
+                           signal: &[f32]) -> Option<()> {
+    let mut vector_writer: VectorWriter<Float> =
+        object_writer.init(key, urids.atom.vector(), urids.atom.float)?;
+    vector_writer.append(signal)?;
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lv2::prelude::*;
+    use lv2_atom::space::RootMutSpace;
+    use urid::mapper::HashURIDMapper;
+
+    /// The URIDs and a buffer of `size` bytes to write the atoms into
+    fn fixture(size: usize) -> (URIDs, Box<[u8]>) {
+        let map = HashURIDMapper::new();
+        (URIDs::from_map(&map).unwrap(), vec![0; size].into_boxed_slice())
+    }
+
+    fn write_object(urids: &URIDs,
+                    header: ObjectHeader,
+                    raw_space: &mut [u8],
+                    write_properties: impl FnOnce(&mut ObjectWriter) -> Option<()>) {
+        let mut space = RootMutSpace::new(raw_space);
+        let mut object_writer = (&mut space as &mut dyn MutSpace)
+            .init(urids.atom.object, header)
+            .unwrap();
+        write_properties(&mut object_writer).unwrap();
+    }
+
+    fn read_object<'a>(urids: &URIDs, raw_space: &'a [u8]) -> (ObjectHeader, ObjectReader<'a>) {
+        UnidentifiedAtom::new(Space::from_slice(raw_space))
+            .read(urids.atom.object, ())
+            .unwrap()
+    }
+
+    fn round_trip<'a>(urids: &URIDs, message: &PluginMessage, raw_space: &'a mut [u8]) -> Option<PluginMessage<'a>> {
+        write_object(urids, message.header(urids), &mut *raw_space, |object_writer| {
+            message.write_properties(urids, object_writer)
+        });
+        let raw_space: &'a [u8] = raw_space;
+        let (header, object_reader) = read_object(urids, raw_space);
+        PluginMessage::read(urids, &header, object_reader)
+    }
+
+    #[test]
+    fn ui_messages() {
+        let (urids, mut raw_space) = fixture(256);
+
+        let messages = [
+            UIMessage::On,
+            UIMessage::Off,
+            UIMessage::RequestConfig,
+            UIMessage::Resolution { buckets_per_second: 2400.0 },
+            UIMessage::ResetStatistics,
+            UIMessage::UpdateRate { updates_per_second: 60.0 },
+        ];
+        for message in messages.iter() {
+            write_object(&urids, message.header(&urids), raw_space.as_mut(), |object_writer| {
+                message.write_properties(&urids, object_writer)
+            });
+            let (header, object_reader) = read_object(&urids, raw_space.as_ref());
+            assert_eq!(UIMessage::read(&urids, &header, object_reader), Some(*message));
+        }
+    }
+
+    #[test]
+    fn plugin_message_is_no_ui_message() {
+        let (urids, mut raw_space) = fixture(256);
+
+        let message = PluginMessage::Config {
+            sample_rate: 48000.0,
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES,
+        };
+        write_object(&urids, message.header(&urids), raw_space.as_mut(), |object_writer| {
+            message.write_properties(&urids, object_writer)
+        });
+        let (header, object_reader) = read_object(&urids, raw_space.as_ref());
+        assert_eq!(UIMessage::read(&urids, &header, object_reader), None);
+    }
+
+    #[test]
+    fn config() {
+        let (urids, mut raw_space) = fixture(256);
+
+        let message = PluginMessage::Config {
+            sample_rate: 44100.0,
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: CAPABILITY_LEARN,
+        };
+        assert_eq!(round_trip(&urids, &message, raw_space.as_mut()), Some(message));
+    }
+
+    #[test]
+    fn config_without_version() {
+        let (urids, mut raw_space) = fixture(256);
+
+        let header = ObjectHeader {
+            id: None,
+            otype: urids.plugin_config.into_general(),
+        };
+        write_object(&urids, header, raw_space.as_mut(), |object_writer| {
+            object_writer.init(urids.parameters.sample_rate, urids.atom.float, 48000.0)?;
+            Some(())
+        });
+        let (header, object_reader) = read_object(&urids, raw_space.as_ref());
+        assert_eq!(PluginMessage::read(&urids, &header, object_reader), Some(PluginMessage::Config {
+            sample_rate: 48000.0,
+            protocol_version: 0,
+            capabilities: 0,
+        }));
+    }
+
+    #[test]
+    fn learn_result() {
+        let (urids, mut raw_space) = fixture(256);
+
+        let message = PluginMessage::LearnResult { attack_smooth: 0.002, sustain_smooth: 0.05 };
+        assert_eq!(round_trip(&urids, &message, raw_space.as_mut()), Some(message));
+    }
+
+    #[test]
+    fn state() {
+        let (urids, mut raw_space) = fixture(256);
+
+        let message = PluginMessage::State(State {
+            attack_point: Some(48000 * 3600 * 24),
+            release_point: None,
+            idle_point: Some(48000 * 3600 * 24 + 512),
+        });
+        assert_eq!(round_trip(&urids, &message, raw_space.as_mut()), Some(message));
+    }
+
+    #[test]
+    fn statistics() {
+        let (urids, mut raw_space) = fixture(512);
+
+        let message = PluginMessage::Statistics(Statistics {
+            hit_count: 128,
+            hits_per_minute: 240.0,
+            tempo: 120.0,
+            attack_length: 0.012,
+            sustain_length: 0.2,
+            max_attack_gain: 9.5,
+            average_attack_gain: 6.0,
+            max_sustain_gain: -12.0,
+            average_sustain_gain: -4.5,
+        });
+        assert_eq!(round_trip(&urids, &message, raw_space.as_mut()), Some(message));
+    }
+
+    #[test]
+    fn audio_data() {
+        let (urids, mut raw_space) = fixture(1024);
+
+        let gain = [0.0, 1.5, 1.5, 3.0];
+        let input = [-12.0, -6.0, -160.0, -150.0];
+        let sidechain = [-20.0, -18.0, -18.0, -16.0];
+        let threshold = [-30.0, -29.0, -29.0, -28.0];
+        let output = [-12.0, -4.5, -158.5, -147.0];
+
+        let message = PluginMessage::AudioData(AudioData {
+            buckets_per_second: 2000.0,
+            first_frame: 4096,
+            gain_signal: &gain,
+            input_signal: &input,
+            sidechain_signal: Some(&sidechain),
+            threshold_signal: Some(&threshold),
+            output_signal: &output,
+            stereo: None,
+            inspector: None,
+        });
+        assert_eq!(round_trip(&urids, &message, raw_space.as_mut()), Some(message));
+    }
+
+    #[test]
+    fn audio_data_without_optional_signals() {
+        let (urids, mut raw_space) = fixture(1024);
+
+        let signal = [-6.0; 4];
+        let message = PluginMessage::AudioData(AudioData {
+            buckets_per_second: 1000.0,
+            first_frame: 0,
+            gain_signal: &signal,
+            input_signal: &signal,
+            sidechain_signal: None,
+            threshold_signal: None,
+            output_signal: &signal,
+            stereo: None,
+            inspector: None,
+        });
+        assert_eq!(round_trip(&urids, &message, raw_space.as_mut()), Some(message));
+    }
+
+    #[test]
+    fn audio_data_with_inspector() {
+        let (urids, mut raw_space) = fixture(1024);
+
+        let signal = [-6.0, -3.0];
+        let attack_fast = [-12.0, -9.0];
+        let attack_slow = [-20.0, -18.0];
+        let release_fast = [-15.0, -14.0];
+        let release_slow = [-16.0, -15.5];
+        let beat_level = [-8.0, -6.0];
+        let pregain = [0.0, 4.5];
+        let message = PluginMessage::AudioData(AudioData {
+            buckets_per_second: 1000.0,
+            first_frame: 0,
+            gain_signal: &signal,
+            input_signal: &signal,
+            sidechain_signal: None,
+            threshold_signal: None,
+            output_signal: &signal,
+            stereo: None,
+            inspector: Some(InspectorSignals {
+                attack_fast: &attack_fast,
+                attack_slow: &attack_slow,
+                release_fast: &release_fast,
+                release_slow: &release_slow,
+                beat_level: &beat_level,
+                pregain: &pregain,
+            }),
+        });
+        assert_eq!(round_trip(&urids, &message, raw_space.as_mut()), Some(message));
+    }
+
+    #[test]
+    fn audio_data_stereo() {
+        let (urids, mut raw_space) = fixture(1024);
+
+        let signal = [-6.0, -3.0];
+        let input_left = [-12.0, -9.0];
+        let input_right = [-20.0, -18.0];
+        let output_left = [-10.0, -7.0];
+        let output_right = [-19.0, -16.0];
+        let message = PluginMessage::AudioData(AudioData {
+            buckets_per_second: 1000.0,
+            first_frame: 0,
+            gain_signal: &signal,
+            input_signal: &signal,
+            sidechain_signal: None,
+            threshold_signal: None,
+            output_signal: &signal,
+            stereo: Some(StereoSignals {
+                input_left: &input_left,
+                input_right: &input_right,
+                output_left: &output_left,
+                output_right: &output_right,
+            }),
+            inspector: None,
+        });
+        assert_eq!(round_trip(&urids, &message, raw_space.as_mut()), Some(message));
+    }
+
+    #[test]
+    fn audio_data_partial_inspector() {
+        let (urids, mut raw_space) = fixture(512);
+
+        let signal = [-6.0, -3.0];
+        let header = ObjectHeader {
+            id: None,
+            otype: urids.audio_data.into_general(),
+        };
+        write_object(&urids, header, raw_space.as_mut(), |object_writer| {
+            object_writer.init(urids.buckets_per_second, urids.atom.float, 1000.0)?;
+            object_writer.init(urids.first_frame, urids.atom.long, 0)?;
+            write_signal(&urids, object_writer, urids.gain_signal, &signal)?;
+            write_signal(&urids, object_writer, urids.input_signal, &signal)?;
+            write_signal(&urids, object_writer, urids.output_signal, &signal)?;
+            write_signal(&urids, object_writer, urids.attack_fast_signal, &signal)?;
+            Some(())
+        });
+        let (header, object_reader) = read_object(&urids, raw_space.as_ref());
+        assert_eq!(PluginMessage::read(&urids, &header, object_reader), None);
+    }
+
+    #[test]
+    fn audio_data_missing_signal() {
+        let (urids, mut raw_space) = fixture(256);
+
+        let header = ObjectHeader {
+            id: None,
+            otype: urids.audio_data.into_general(),
+        };
+        write_object(&urids, header, raw_space.as_mut(), |object_writer| {
+            object_writer.init(urids.buckets_per_second, urids.atom.float, 1000.0)?;
+            object_writer.init(urids.first_frame, urids.atom.long, 0)?;
+            Some(())
+        });
+        let (header, object_reader) = read_object(&urids, raw_space.as_ref());
+        assert_eq!(PluginMessage::read(&urids, &header, object_reader), None);
+    }
+
+    #[test]
+    fn overflow() {
+        let (urids, mut raw_space) = fixture(256);
+
+        let message = PluginMessage::Overflow { dropped_messages: 3 };
+        assert_eq!(round_trip(&urids, &message, raw_space.as_mut()), Some(message));
+    }
+
+    #[test]
+    fn message_sizes() {
+        let (urids, mut raw_space) = fixture(1024);
+
+        let signal = [-6.0; 6];
+        let messages = [
+            PluginMessage::Config {
+                sample_rate: 48000.0,
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: CAPABILITIES,
+            },
+            PluginMessage::LearnResult { attack_smooth: 0.002, sustain_smooth: 0.05 },
+            PluginMessage::State(State {
+                attack_point: Some(4096),
+                release_point: None,
+                idle_point: Some(8192),
+            }),
+            PluginMessage::Statistics(Statistics::default()),
+            PluginMessage::AudioData(AudioData {
+                buckets_per_second: 1000.0,
+                first_frame: 0,
+                gain_signal: &signal,
+                input_signal: &signal,
+                sidechain_signal: None,
+                threshold_signal: Some(&signal),
+                output_signal: &signal,
+                stereo: None,
+                inspector: None,
+            }),
+            PluginMessage::AudioData(AudioData {
+                buckets_per_second: 1000.0,
+                first_frame: 0,
+                gain_signal: &signal,
+                input_signal: &signal,
+                sidechain_signal: Some(&signal),
+                threshold_signal: None,
+                output_signal: &signal,
+                stereo: None,
+                inspector: Some(InspectorSignals {
+                    attack_fast: &signal,
+                    attack_slow: &signal,
+                    release_fast: &signal,
+                    release_slow: &signal,
+                    beat_level: &signal,
+                    pregain: &signal,
+                }),
+            }),
+            PluginMessage::Overflow { dropped_messages: 1 },
+        ];
+        for message in messages.iter() {
+            {
+                let mut space = RootMutSpace::new(raw_space.as_mut());
+                let mut sequence_writer = (&mut space as &mut dyn MutSpace)
+                    .init(urids.atom.sequence, TimeStampURID::Frames(urids.unit.frame))
+                    .unwrap();
+                message.write(&urids, &mut sequence_writer).unwrap();
+            }
+            // the size of the sequence body is the sequence body header and
+            // the event
+            let mut size = [0u8; 4];
+            size.copy_from_slice(&raw_space[..4]);
+            assert_eq!(u32::from_ne_bytes(size) as usize, 8 + message.size());
+        }
+    }
+
+    #[test]
+    fn audio_data_size() {
+        let signal = [-6.0; 8];
+        let audio_data = AudioData {
+            buckets_per_second: 1000.0,
+            first_frame: 0,
+            gain_signal: &signal,
+            input_signal: &signal,
+            sidechain_signal: Some(&signal),
+            threshold_signal: Some(&signal),
+            output_signal: &signal,
+            stereo: None,
+            inspector: None,
+        };
+        assert_eq!(PluginMessage::AudioData(audio_data).size(), AudioData::max_size(4, false, false));
+        assert_eq!(AudioData::max_buckets(AudioData::max_size(4, false, false), false, false), 4);
+        assert_eq!(AudioData::max_buckets(AudioData::max_size(4, false, false) - 1, false, false), 3);
+        assert_eq!(AudioData::max_buckets(0, false, false), 0);
+
+        let truncated = audio_data.truncated(3);
+        assert_eq!(truncated.len(), 3);
+        assert_eq!(truncated.threshold_signal.unwrap().len(), 6);
+        assert_eq!(audio_data.truncated(10).len(), 4);
+
+        let audio_data = AudioData {
+            inspector: Some(InspectorSignals {
+                attack_fast: &signal,
+                attack_slow: &signal,
+                release_fast: &signal,
+                release_slow: &signal,
+                beat_level: &signal,
+                pregain: &signal,
+            }),
+            ..audio_data
+        };
+        assert_eq!(PluginMessage::AudioData(audio_data).size(), AudioData::max_size(4, false, true));
+        assert_eq!(AudioData::max_buckets(AudioData::max_size(4, false, true), false, true), 4);
+        assert_eq!(audio_data.truncated(3).inspector.unwrap().pregain.len(), 6);
+
+        let audio_data = AudioData {
+            stereo: Some(StereoSignals {
+                input_left: &signal,
+                input_right: &signal,
+                output_left: &signal,
+                output_right: &signal,
+            }),
+            ..audio_data
+        };
+        assert_eq!(PluginMessage::AudioData(audio_data).size(), AudioData::max_size(4, true, true));
+        assert_eq!(AudioData::max_buckets(AudioData::max_size(4, true, true), true, true), 4);
+        assert_eq!(audio_data.truncated(3).stereo.unwrap().output_right.len(), 6);
+    }
+
+    #[test]
+    fn bucket_pairs() {
+        let signal = [-12.0, -6.0, -24.0, -3.0];
+        assert_eq!(buckets(&signal).collect::<Vec<_>>(), vec![(-12.0, -6.0), (-24.0, -3.0)]);
+    }
+}
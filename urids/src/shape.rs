@@ -0,0 +1,27 @@
+//! The curve that reshapes the detector difference before the boost is
+//! applied
+//!
+//! The plugin applies it and the UI draws it, so both use this module.
+
+/// The range in dB of the normalized detector difference `reshape()`
+/// reshapes. Beyond it the curve is the identity again.
+pub const SHAPE_RANGE: f64 = 1.0;
+
+/// The exponent of `reshape()` for a shape parameter from -1.0 to 1.0
+pub fn shape_exponent(shape: f64) -> f64 {
+    4.0f64.powf(-shape)
+}
+
+/// Reshapes the detector difference `x` in dB, which is normalized to about
+/// 0.0..1.0. Its position within `SHAPE_RANGE` is raised to the power of
+/// `exponent`. Below 1.0 the gain rises earlier and steeper, above 1.0 later
+/// and gentler. Larger differences, which the sustain compensation can
+/// produce, are left as they are, so that the curve stays continuous and
+/// bounded.
+pub fn reshape(x: f64, exponent: f64) -> f64 {
+    let position = x.abs() / SHAPE_RANGE;
+    if position >= 1.0 {
+        return x;
+    }
+    x.signum() * SHAPE_RANGE * position.powf(exponent)
+}